@@ -1,5 +1,55 @@
 extern crate skeptic;
 
 fn main() {
-    skeptic::generate_doc_tests(&["README.md", "template-example.md", "tests/hashtag-test.md", "tests/should-panic-test.md"]);
+    skeptic::generate_doc_tests(&[
+        "README.md",
+        "template-example.md",
+        "tests/hashtag-test.md",
+        "tests/should-panic-test.md",
+        "tests/compile-fail-test.md",
+        "tests/edition-test.md",
+        "tests/default-edition-test.md",
+        "tests/tilde-fence-test.md",
+        "tests/rs-alias-test.md",
+        "tests/template-placeholder-test.md",
+        "tests/inline-template-test.md",
+        "tests/expect-output-test.md",
+        "tests/include-test.md",
+        "tests/space-separated-test.md",
+        "tests/extern-test.md",
+        "tests/crate-attributes-test.md",
+        "tests/cwd-test.md",
+        "tests/requires-feature-test.md",
+        "tests/block-quote-test.md",
+        "tests/segment-test.md",
+        "tests/aux-test.md",
+        "tests/front-matter-mode-test.md",
+        "tests/exit-code-test.md",
+        "tests/doc-comments/answer.rs",
+    ]);
+
+    skeptic::SkepticBuilder::new()
+        .docs(&["tests/module-test.md"])
+        .out_file("skeptic-module-tests.rs")
+        .module_per_doc(true)
+        .generate();
+
+    skeptic::SkepticBuilder::new()
+        .docs(&["tests/default-template-test.md"])
+        .out_file("skeptic-default-template-tests.rs")
+        .default_template("tests/default-template.in")
+        .generate();
+
+    skeptic::SkepticBuilder::new()
+        .docs(&["tests/wrap-module-test.md"])
+        .out_file("skeptic-wrapped-tests.rs")
+        .wrap_module("skeptic_tests")
+        .ignore_with_cfg("tarpaulin")
+        .generate();
+
+    skeptic::SkepticBuilder::new()
+        .docs(&["tests/html-code-test.md"])
+        .out_file("skeptic-html-tests.rs")
+        .html_code_blocks(true)
+        .generate();
 }
@@ -1,46 +1,551 @@
 extern crate pulldown_cmark as cmark;
 extern crate tempdir;
+extern crate unicode_xid;
 
 use std::env;
-use std::fs::File;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
 use std::io::{self, Read, Write, Error as IoError};
-use std::path::{PathBuf, Path};
+use std::mem;
+use std::path::{Component, PathBuf, Path};
+use std::process;
+use std::sync::Arc;
 use cmark::{Parser, Event, Tag};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use unicode_xid::UnicodeXID;
 
-pub fn generate_doc_tests<T: Clone>(docs: &[T]) where T : AsRef<str> {
-    // This shortcut is specifically so examples in skeptic's on
-    // readme can call this function in non-build.rs contexts, without
-    // panicking below.
-    if docs.is_empty() {
-        return;
+/// Generates the tests of the documents, and returns how many there are, so
+/// that a build script can check examples weren't lost when the documents
+/// changed.
+pub fn generate_doc_tests<T: Clone + AsRef<str>>(docs: &[T]) -> usize {
+    generate_doc_tests_named(docs, DEFAULT_OUT_FILE)
+}
+
+/// Like `generate_doc_tests`, but writes the tests to `out_file_name` in
+/// `OUT_DIR` instead of `skeptic-tests.rs`, so that the tests of several
+/// calls can be included separately.
+pub fn generate_doc_tests_named<T: Clone + AsRef<str>>(docs: &[T], out_file_name: &str) -> usize {
+    SkepticBuilder::new().docs(docs).out_file(out_file_name).generate()
+}
+
+/// The file in `OUT_DIR` the tests are written to by default.
+const DEFAULT_OUT_FILE: &str = "skeptic-tests.rs";
+
+/// Like `generate_doc_tests`, but returns errors instead of panicking.
+pub fn try_generate_doc_tests<T: Clone + AsRef<str>>(docs: &[T]) -> Result<usize, SkepticError> {
+    SkepticBuilder::new().docs(docs).try_generate()
+}
+
+/// Checks the documents and their templates without generating any tests,
+/// and returns every problem found: documents that can't be read or have
+/// malformed examples, examples using templates that don't exist, and
+/// templates that are malformed or have no placeholder for the code of the
+/// examples. The documents are relative to `CARGO_MANIFEST_DIR`, or to the
+/// current directory when it isn't set.
+pub fn validate<T: AsRef<str>>(docs: &[T]) -> Result<(), Vec<ValidationError>> {
+    let root_dir = default_root_dir();
+    let docs = docs.iter()
+        .map(|doc| doc.as_ref().to_string())
+        .filter(|doc| !doc.ends_with(".skt.md"))
+        .collect::<Vec<_>>();
+
+    let mut errors = Vec::new();
+    for (doc, name_path) in docs.iter().zip(name_paths(&docs)) {
+        let path = root_dir.join(doc);
+        let doc_test = match extract_tests_from_file(&path, &name_path, false, false) {
+            Ok(doc_test) => doc_test,
+            Err(e) => {
+                errors.push(ValidationError::Document(e));
+                continue;
+            }
+        };
+        for test in &doc_test.tests {
+            if let Some(ref t) = test.template {
+                if !doc_test.templates.contains_key(t) {
+                    errors.push(ValidationError::Document(template_not_found(&doc_test, test, t)));
+                }
+            }
+        }
+        let mut templates = doc_test.templates.iter().collect::<Vec<_>>();
+        templates.sort();
+        let templates = templates.into_iter()
+            .map(|(t, template)| (format!("`skt-{}` of {}", t, doc_test.path.display()), template))
+            .chain(doc_test.old_template.iter().map(|template| {
+                (format!("`skeptic-template` of {}", doc_test.path.display()), template)
+            }));
+        for (name, template) in templates {
+            if let Err(e) = check_template(&name, template) {
+                errors.push(ValidationError::Template(e));
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// The directory the documents of `validate` and `list_tests` are relative
+/// to, `CARGO_MANIFEST_DIR`, or the current directory when it isn't set.
+fn default_root_dir() -> PathBuf {
+    env::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from).unwrap_or_default()
+}
+
+/// The ways generating the doc tests can fail.
+#[derive(Debug)]
+pub enum SkepticError {
+    /// An environment variable cargo sets for build scripts is missing.
+    MissingEnvVar(String),
+    /// Reading a document or template, or writing the tests, failed.
+    Io {
+        path: PathBuf,
+        error: IoError,
+    },
+    /// An example uses a `skt-` template its document doesn't define.
+    TemplateNotFound {
+        path: PathBuf,
+        line: usize,
+        test: String,
+        template: String,
+        /// The templates the document does define.
+        available: Vec<String>,
+    },
+    /// An example or template is malformed. The message says where.
+    Invalid(String),
+    /// Several documents couldn't be read or have malformed examples. Each
+    /// is the first error of one of them.
+    Multiple(Vec<SkepticError>),
+}
+
+impl SkepticError {
+    fn io(path: &Path, error: IoError) -> SkepticError {
+        SkepticError::Io {
+            path: path.to_owned(),
+            error,
+        }
+    }
+}
+
+impl fmt::Display for SkepticError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SkepticError::MissingEnvVar(ref name) => {
+                write!(f, "environment variable {} is not set, \
+                           skeptic must be run from a build script", name)
+            }
+            SkepticError::Io { ref path, ref error } => {
+                write!(f, "{}: {}", path.display(), error)
+            }
+            SkepticError::TemplateNotFound { ref path, line, ref test, ref template, ref available } => {
+                write!(f, "{}:{}: template `skt-{}` not found for test {}, ",
+                       path.display(), line, template, test)?;
+                if available.is_empty() {
+                    write!(f, "no templates are defined")
+                } else {
+                    let available = available.iter()
+                        .map(|t| format!("`skt-{}`", t))
+                        .collect::<Vec<_>>();
+                    write!(f, "available templates are {}", available.join(", "))
+                }
+            }
+            SkepticError::Invalid(ref message) => f.write_str(message),
+            SkepticError::Multiple(ref errors) => {
+                write!(f, "{} documents have errors:", errors.len())?;
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for SkepticError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SkepticError::Io { ref error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// A problem found by `validate`.
+#[derive(Debug)]
+pub enum ValidationError {
+    /// A document can't be read, has a malformed example, or an example
+    /// that uses a template which doesn't exist.
+    Document(SkepticError),
+    /// A template is malformed, or has no placeholder for the code of the
+    /// examples.
+    Template(SkepticError),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValidationError::Document(ref e) | ValidationError::Template(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for ValidationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ValidationError::Document(ref e) | ValidationError::Template(ref e) => Some(e),
+        }
+    }
+}
+
+/// Configures and generates the doc tests, for build scripts that need more
+/// control than `generate_doc_tests` gives.
+///
+/// By default the tests are written to `skeptic-tests.rs` in `OUT_DIR`, and
+/// the documents are found relative to `CARGO_MANIFEST_DIR`.
+#[derive(Clone, Debug, Default)]
+pub struct SkepticBuilder {
+    out_file: Option<PathBuf>,
+    root_dir: Option<PathBuf>,
+    docs: Vec<String>,
+    module_per_doc: bool,
+    default_template: Option<PathBuf>,
+    wrap_module: Option<String>,
+    test_unlabeled_blocks: bool,
+    no_rerun_directives: bool,
+    default_allows: Option<Vec<String>>,
+    json_manifest: bool,
+    require_tests_per_doc: bool,
+    transform: Option<Transform>,
+    ignore_cfg: Option<String>,
+    html_code_blocks: bool,
+}
+
+/// A function rewriting the code of every example, set with
+/// `SkepticBuilder::transform`.
+#[derive(Clone)]
+struct Transform(Arc<dyn Fn(&str) -> String + Send + Sync>);
+
+impl fmt::Debug for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Transform(..)")
     }
+}
 
-    let docs = docs.iter().cloned().filter(|d| {
-        !d.as_ref().ends_with(".skt.md")
-    }).collect::<Vec<_>>();
+impl SkepticBuilder {
+    pub fn new() -> SkepticBuilder {
+        SkepticBuilder::default()
+    }
 
-    // Inform cargo that it needs to rerun the build script if one of the skeptic files are
-    // modified
-    for doc in &docs {
-        println!("cargo:rerun-if-changed={}", doc.as_ref());
-        println!("cargo:rerun-if-changed={}.skt.md", doc.as_ref());
+    /// Sets the file the tests are written to. A relative path is relative
+    /// to `OUT_DIR`.
+    pub fn out_file<P: Into<PathBuf>>(mut self, out_file: P) -> SkepticBuilder {
+        self.out_file = Some(out_file.into());
+        self
     }
 
-    let out_dir = env::var("OUT_DIR").unwrap();
-    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    /// Sets the directory the documents are relative to.
+    pub fn root_dir<P: Into<PathBuf>>(mut self, root_dir: P) -> SkepticBuilder {
+        self.root_dir = Some(root_dir.into());
+        self
+    }
 
-    let mut out_file = PathBuf::from(out_dir.clone());
-    out_file.push("skeptic-tests.rs");
+    /// Adds documents to generate tests for.
+    pub fn docs<I>(mut self, docs: I) -> SkepticBuilder
+        where I: IntoIterator,
+              I::Item: AsRef<str>
+    {
+        self.docs.extend(docs.into_iter().map(|d| d.as_ref().to_string()));
+        self
+    }
 
-    let config = Config {
-        out_dir: PathBuf::from(out_dir),
-        root_dir: PathBuf::from(cargo_manifest_dir),
-        out_file: out_file,
-        docs: docs.iter().map(|s| s.as_ref().to_string()).collect(),
+    /// Puts the tests of each document in a module named after it, instead
+    /// of putting all tests at the top level of the generated file.
+    pub fn module_per_doc(mut self, module_per_doc: bool) -> SkepticBuilder {
+        self.module_per_doc = module_per_doc;
+        self
+    }
+
+    /// Tests the code blocks without an info string, as rustdoc does,
+    /// instead of only those marked `rust`.
+    pub fn test_unlabeled_blocks(mut self, test_unlabeled_blocks: bool) -> SkepticBuilder {
+        self.test_unlabeled_blocks = test_unlabeled_blocks;
+        self
+    }
+
+    /// Puts all the tests in a module of the given name, and leaves out the
+    /// `extern crate skeptic;` of the generated file, which the file the
+    /// tests are included in must then declare.
+    pub fn wrap_module<S: Into<String>>(mut self, name: S) -> SkepticBuilder {
+        self.wrap_module = Some(name.into());
+        self
+    }
+
+    /// Sets the lints allowed in every example, `dead_code` and
+    /// `unused_variables` by default, like rustdoc, which examples often
+    /// trigger without being wrong. Examples tagged `check` allow none.
+    pub fn default_allows<I>(mut self, lints: I) -> SkepticBuilder
+        where I: IntoIterator,
+              I::Item: AsRef<str>
+    {
+        self.default_allows = Some(lints.into_iter().map(|l| l.as_ref().to_string()).collect());
+        self
+    }
+
+    /// Sets a function that rewrites the code of every example before it
+    /// is compiled, e.g. to replace a placeholder with the name of the
+    /// crate. It is given the whole code rustc compiles, template
+    /// included, and should keep its lines, so that errors point at the
+    /// right lines of the document.
+    pub fn transform<F>(mut self, transform: F) -> SkepticBuilder
+        where F: Fn(&str) -> String + Send + Sync + 'static
+    {
+        self.transform = Some(Transform(Arc::new(transform)));
+        self
+    }
+
+    /// Ignores every test when the crate is compiled with the given cfg,
+    /// like `tarpaulin`, which coverage tools set, and can't make sense of
+    /// examples compiled and run in processes of their own. A cfg that is
+    /// a single name is declared to rustc's cfg checks.
+    pub fn ignore_with_cfg<S: Into<String>>(mut self, cfg: S) -> SkepticBuilder {
+        self.ignore_cfg = Some(cfg.into());
+        self
+    }
+
+    /// Also tests the `<pre><code class="language-rust">` sections of the
+    /// HTML blocks of the documents, as written by tools exporting HTML,
+    /// with their entities decoded. Their other classes are tags, e.g.
+    /// `class="language-rust no_run"`. Off by default, since finding them
+    /// in HTML is a heuristic.
+    pub fn html_code_blocks(mut self, html_code_blocks: bool) -> SkepticBuilder {
+        self.html_code_blocks = html_code_blocks;
+        self
+    }
+
+    /// Makes a document without any tested example an error, so that
+    /// removing the `rust` tags of all its examples doesn't silently leave
+    /// it untested. Off by default, since some documents are only prose.
+    pub fn require_tests_per_doc(mut self, require_tests_per_doc: bool) -> SkepticBuilder {
+        self.require_tests_per_doc = require_tests_per_doc;
+        self
+    }
+
+    /// Also writes a JSON description of the generated tests next to the
+    /// file they are written to, `skeptic-tests.json` by default, for tools
+    /// that map test results back to the examples. Its format is described
+    /// on `json_manifest` in the source.
+    pub fn json_manifest(mut self, json_manifest: bool) -> SkepticBuilder {
+        self.json_manifest = json_manifest;
+        self
+    }
+
+    /// Whether to print the `cargo:rerun-if-changed` and
+    /// `cargo:rerun-if-env-changed` directives for the documents, their
+    /// templates and the environment variables skeptic reads, which it does
+    /// by default. Build scripts that track their inputs themselves can
+    /// turn them off.
+    pub fn rerun_directives(mut self, rerun_directives: bool) -> SkepticBuilder {
+        self.no_rerun_directives = !rerun_directives;
+        self
+    }
+
+    /// Sets a file, relative to the root directory, whose contents are the
+    /// template of the examples of documents without a `skeptic-template`
+    /// block of their own. Examples that use a `skt-` template are not
+    /// affected.
+    pub fn default_template<P: Into<PathBuf>>(mut self, path: P) -> SkepticBuilder {
+        self.default_template = Some(path.into());
+        self
+    }
+
+    /// Generates the tests, panicking on error, and returns how many there
+    /// are, including ignored ones.
+    pub fn generate(self) -> usize {
+        match self.try_generate() {
+            Ok(count) => count,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Generates the tests, returning any error instead of panicking.
+    pub fn try_generate(self) -> Result<usize, SkepticError> {
+        // This shortcut is specifically so examples in skeptic's on
+        // readme can call this function in non-build.rs contexts, without
+        // panicking below.
+        if self.docs.is_empty() {
+            return Ok(0);
+        }
+
+        let root_dir = match self.root_dir {
+            Some(root_dir) => root_dir,
+            None => PathBuf::from(env_var("CARGO_MANIFEST_DIR")?),
+        };
+
+        let mut docs = Vec::new();
+        // The directories globs were matched in, where new documents may be
+        // added
+        let mut glob_dirs = Vec::new();
+        for doc in self.docs {
+            if doc.contains(['*', '?']) {
+                let components = doc.split('/').collect::<Vec<_>>();
+                let mut matches = Vec::new();
+                expand_glob(&root_dir, Path::new(""), &components, &mut matches, &mut glob_dirs)
+                    .map_err(|e| SkepticError::io(&root_dir, e))?;
+                if matches.is_empty() {
+                    return Err(SkepticError::Invalid(
+                        format!("no documents in {} match `{}`", root_dir.display(), doc)));
+                }
+                matches.sort();
+                docs.extend(matches);
+            } else {
+                docs.push(doc);
+            }
+        }
+        let mut seen = HashSet::new();
+        docs.retain(|d| !d.ends_with(".skt.md") && seen.insert(d.clone()));
+
+        // Inform cargo that it needs to rerun the build script if one of the skeptic files are
+        // modified
+        let rerun_directives = !self.no_rerun_directives;
+        if rerun_directives {
+            for doc in &docs {
+                let doc = root_dir.join(doc);
+                println!("cargo:rerun-if-changed={}", doc.display());
+                for template in template_paths(&doc) {
+                    println!("cargo:rerun-if-changed={}", template.display());
+                }
+            }
+
+            println!("cargo:rerun-if-env-changed=SKEPTIC_FILTER");
+            println!("cargo:rerun-if-env-changed=SKEPTIC_WARN_IGNORED");
+            println!("cargo:rerun-if-env-changed=SKEPTIC_SUMMARY");
+            println!("cargo:rerun-if-env-changed=SKEPTIC_BATCH");
+            glob_dirs.sort();
+            glob_dirs.dedup();
+            for dir in &glob_dirs {
+                println!("cargo:rerun-if-changed={}", dir.display());
+            }
+        }
+
+        // So that rustc doesn't warn about a cfg it doesn't know of
+        if let Some(ref cfg) = self.ignore_cfg {
+            let is_name = cfg.starts_with(|c: char| c == '_' || UnicodeXID::is_xid_start(c)) &&
+                          cfg.chars().all(UnicodeXID::is_xid_continue);
+            if is_name {
+                println!("cargo:rustc-check-cfg=cfg({})", cfg);
+            }
+        }
+
+        let out_dir = env_var("OUT_DIR")?;
+
+        let default_template = self.default_template.map(|path| root_dir.join(path));
+        match default_template {
+            Some(ref path) if rerun_directives => {
+                println!("cargo:rerun-if-changed={}", path.display());
+            }
+            _ => {}
+        }
+
+        let out_file = PathBuf::from(out_dir.clone())
+            .join(self.out_file.unwrap_or_else(|| PathBuf::from(DEFAULT_OUT_FILE)));
+
+        // Examples are compiled for the target the crate is built for, which
+        // is only given explicitly when cross-compiling.
+        let target = match (env::var("TARGET"), env::var("HOST")) {
+            (Ok(target), Ok(host)) if target != host => Some(target),
+            _ => None,
+        };
+
+        let config = Config {
+            out_dir: PathBuf::from(out_dir),
+            root_dir,
+            out_file,
+            docs,
+            module_per_doc: self.module_per_doc,
+            default_template,
+            wrap_module: self.wrap_module,
+            test_unlabeled_blocks: self.test_unlabeled_blocks,
+            require_tests_per_doc: self.require_tests_per_doc,
+            transform: self.transform,
+            ignore_cfg: self.ignore_cfg,
+            html_code_blocks: self.html_code_blocks,
+            json_manifest: self.json_manifest,
+            rerun_directives,
+            default_allows: self.default_allows.unwrap_or_else(|| {
+                vec![String::from("dead_code"), String::from("unused_variables")]
+            }),
+            crate_name: env::var("CARGO_PKG_NAME").ok(),
+            crate_version: env::var("CARGO_PKG_VERSION").ok(),
+            target,
+            filter: env::var("SKEPTIC_FILTER").ok().filter(|f| !f.is_empty()),
+            batch: env_flag("SKEPTIC_BATCH"),
+        };
+
+        run(config)
+    }
+}
+
+/// Adds the files under `root_dir.join(dir)` whose path relative to
+/// `root_dir` matches the glob, split into the `components` of `/`-separated
+/// paths, to `matches`, and the directories that were searched to `dirs`.
+/// A component can have `*` and `?` wildcards, or be `**`, which matches
+/// any number of directories. Hidden files only match components which
+/// start with `.` themselves.
+fn expand_glob(root_dir: &Path,
+               dir: &Path,
+               components: &[&str],
+               matches: &mut Vec<String>,
+               dirs: &mut Vec<PathBuf>)
+               -> io::Result<()> {
+    let (component, rest) = match components.split_first() {
+        Some(split) => split,
+        None => {
+            if root_dir.join(dir).is_file() {
+                matches.push(dir.to_string_lossy().into_owned());
+            }
+            return Ok(());
+        }
     };
+    if !component.contains(['*', '?']) {
+        let path = dir.join(component);
+        if root_dir.join(&path).exists() {
+            expand_glob(root_dir, &path, rest, matches, dirs)?;
+        }
+        return Ok(());
+    }
+    if !root_dir.join(dir).is_dir() {
+        return Ok(());
+    }
+    dirs.push(root_dir.join(dir));
+    if *component == "**" {
+        expand_glob(root_dir, dir, rest, matches, dirs)?;
+    }
+    for entry in fs::read_dir(root_dir.join(dir))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && !component.starts_with('.') {
+            continue;
+        }
+        if *component == "**" {
+            if entry.file_type()?.is_dir() {
+                expand_glob(root_dir, &dir.join(&name), components, matches, dirs)?;
+            }
+        } else if glob_matches(component, &name) {
+            expand_glob(root_dir, &dir.join(&name), rest, matches, dirs)?;
+        }
+    }
+    Ok(())
+}
+
+fn env_var(name: &str) -> Result<String, SkepticError> {
+    env::var(name).map_err(|_| SkepticError::MissingEnvVar(name.to_string()))
+}
 
-    run(config);
+/// Whether the environment variable is set to something other than `0`.
+fn env_flag(name: &str) -> bool {
+    env::var(name).map(|v| !v.is_empty() && v != "0").unwrap_or(false)
 }
 
 struct Config {
@@ -48,135 +553,842 @@ struct Config {
     root_dir: PathBuf,
     out_file: PathBuf,
     docs: Vec<String>,
+    module_per_doc: bool,
+    default_template: Option<PathBuf>,
+    wrap_module: Option<String>,
+    test_unlabeled_blocks: bool,
+    /// Whether a document without tests is an error.
+    require_tests_per_doc: bool,
+    /// Rewrites the code of each example, after its template is applied.
+    transform: Option<Transform>,
+    /// The cfg under which all the tests are ignored.
+    ignore_cfg: Option<String>,
+    /// Whether the `<pre><code>` sections of HTML blocks are code blocks.
+    html_code_blocks: bool,
+    /// Whether to also write `out_file` with a `json` extension.
+    json_manifest: bool,
+    /// Whether to print `cargo:rerun-if-changed` for the files the examples
+    /// include.
+    rerun_directives: bool,
+    /// The lints allowed in the examples that aren't `check`ed.
+    default_allows: Vec<String>,
+    /// The name and version of the crate, for `{crate_name}` and
+    /// `{crate_version}` in templates.
+    crate_name: Option<String>,
+    crate_version: Option<String>,
+    target: Option<String>,
+    filter: Option<String>,
+    /// Whether to emit the batches `SKEPTIC_BATCH` compiles the examples in.
+    batch: bool,
 }
 
-fn run(ref config: Config) {
-    let tests = extract_tests(config).unwrap();
-    emit_tests(config, tests).unwrap();
+fn run(ref config: Config) -> Result<usize, SkepticError> {
+    let tests = extract_tests(&config.root_dir,
+                              &config.docs,
+                              config.test_unlabeled_blocks,
+                              config.require_tests_per_doc,
+                              config.html_code_blocks)?;
+    if config.rerun_directives {
+        for path in tests.inputs() {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+    if env_flag("SKEPTIC_WARN_IGNORED") {
+        for doc_test in &tests.doc_tests {
+            for test in doc_test.tests.iter().filter(|test| test.ignore) {
+                let reason = test.ignore_reason.as_ref().map_or(String::new(), |r| format!(": {}", r));
+                println!("cargo:warning={}:{}: example {} is ignored{}",
+                         doc_test.path.display(), test.line, test.name, reason);
+            }
+        }
+    }
+    emit_tests(config, tests)
 }
 
-struct Test {
-    name: String,
-    text: Vec<String>,
-    ignore: bool,
-    no_run: bool,
-    should_panic: bool,
-    template: Option<String>,
+/// What a test generated for an example would be, as returned by
+/// `list_tests`.
+#[derive(Clone, Debug)]
+pub struct TestSummary {
+    /// The name of the test function.
+    pub name: String,
+    /// The document the example is in.
+    pub document: PathBuf,
+    /// The line of the document on which the code of the example starts.
+    pub line: usize,
+    pub ignore: bool,
+    pub no_run: bool,
+    pub should_panic: bool,
+    pub compile_fail: bool,
+    /// The name of the `skt-` template the example uses.
+    pub template: Option<String>,
+}
+
+/// Lists the tests `generate_doc_tests` would generate for the documents,
+/// without generating them. The documents are relative to
+/// `CARGO_MANIFEST_DIR` if it's set, and to the current directory otherwise.
+pub fn list_tests<T: AsRef<str>>(docs: &[T]) -> Result<Vec<TestSummary>, SkepticError> {
+    let root_dir = default_root_dir();
+    let docs = docs.iter()
+        .map(|d| d.as_ref().to_string())
+        .filter(|d| !d.ends_with(".skt.md"))
+        .collect::<Vec<_>>();
+    let suite = extract_tests(&root_dir, &docs, false, false, false)?;
+
+    let mut summaries = Vec::new();
+    for doc_test in suite.doc_tests {
+        for test in doc_test.tests {
+            summaries.push(TestSummary {
+                name: test.name,
+                document: doc_test.path.clone(),
+                line: test.line,
+                ignore: test.ignore,
+                no_run: test.no_run,
+                should_panic: test.should_panic,
+                compile_fail: test.compile_fail,
+                template: test.template,
+            });
+        }
+    }
+    Ok(summaries)
+}
+
+/// A Rust code block extracted from a markdown document, along with the
+/// attributes parsed from its info string.
+#[derive(Clone, Debug)]
+pub struct ExtractedTest {
+    /// The name of the generated test function.
+    pub name: String,
+    /// The code of the block, including any lines hidden with `# `.
+    pub text: String,
+    /// The line of the document on which the code of the block starts.
+    pub line: usize,
+    pub ignore: bool,
+    /// The reason given by `ignore(...)`.
+    pub ignore_reason: Option<String>,
+    pub no_run: bool,
+    pub should_panic: bool,
+    /// The fragment of the panic message given by `should_panic(expected = "...")`.
+    pub expected_panic: Option<String>,
+    pub compile_fail: bool,
+    /// The name of the `skt-` template the block uses.
+    pub template: Option<String>,
+    /// The edition given by an `edition20XX` tag.
+    pub edition: Option<String>,
+    /// The stdout the example must print, from the code block following an
+    /// `expect_output` block.
+    pub expected_output: Option<String>,
+    /// Text the stderr of the example must contain, from the code block
+    /// following an `expect_stderr` block, after its expected output if any.
+    pub expected_stderr: Option<String>,
+    /// The crates given by `extern=` tags.
+    pub externs: Vec<String>,
+    /// The rustc error codes a `compile_fail` block must fail with, from
+    /// `E0308`-style tags.
+    pub error_codes: Vec<String>,
+    /// Whether the example is run in the directory of the crate's manifest,
+    /// from `cwd=manifest`, instead of a temporary directory.
+    pub cwd_manifest: bool,
+    /// The cargo features the test is only compiled with, from
+    /// `requires-feature=` tags.
+    pub required_features: Vec<String>,
+    /// Whether the test is ignored when the tests are run under Miri, from
+    /// `no_miri`.
+    pub no_miri: bool,
+    /// Whether the example must compile without warnings, unused code
+    /// included, from `check`.
+    pub check: bool,
+    /// Whether the example is compiled with `-C panic=abort`, from
+    /// `panic_abort`.
+    pub panic_abort: bool,
+    /// The files, from `aux=` tags relative to the document, that are
+    /// copied next to the example so that it can declare them as modules.
+    pub aux_files: Vec<PathBuf>,
+    /// Whether the example is only type-checked, without generating code
+    /// or linking it, from `check_only`.
+    pub check_only: bool,
+    /// The code the example must exit with, from `exit_code=`.
+    pub exit_code: Option<i32>,
 }
 
 struct DocTestSuite {
     doc_tests: Vec<DocTest>,
 }
 
+impl DocTestSuite {
+    /// The files included by the examples of all the documents, each once.
+    fn inputs(&self) -> Vec<&Path> {
+        let mut inputs: Vec<&Path> = self.doc_tests.iter()
+            .flat_map(|doc_test| doc_test.inputs.iter().map(|path| path.as_path()))
+            .collect();
+        inputs.sort();
+        inputs.dedup();
+        inputs
+    }
+}
+
+#[derive(Debug)]
 struct DocTest {
     path: PathBuf,
+    /// The end of `path` the tests and module of the document are named after.
+    name_path: PathBuf,
     old_template: Option<String>,
-    tests: Vec<Test>,
+    /// The edition of the examples without an edition tag, from a
+    /// `default-edition20XX` tag on the first one.
+    default_edition: Option<String>,
+    /// `ignore` or `no_run`, which the examples without a tag saying how
+    /// they are tested default to, from the front matter.
+    default_mode: Option<String>,
+    tests: Vec<ExtractedTest>,
     templates: HashMap<String, String>,
+    /// The files the examples include or copy with `aux=`, which the tests
+    /// are regenerated after changing.
+    inputs: Vec<PathBuf>,
 }
 
-fn extract_tests(config: &Config) -> Result<DocTestSuite, IoError> {
+/// Extracts the tests of the documents, relative to `root_dir`. With
+/// `require_tests`, a document without any example is an error.
+fn extract_tests(root_dir: &Path,
+                 docs: &[String],
+                 test_unlabeled_blocks: bool,
+                 require_tests: bool,
+                 html_code_blocks: bool)
+                 -> Result<DocTestSuite, SkepticError> {
     let mut doc_tests = Vec::new();
-    for doc in &config.docs {
-        let ref mut path = config.root_dir.clone();
+    // The documents are all extracted so that the errors of each are
+    // reported at once
+    let mut errors = Vec::new();
+    for (doc, name_path) in docs.iter().zip(name_paths(docs)) {
+        let mut path = root_dir.to_owned();
         path.push(doc);
-        let new_tests = try!(extract_tests_from_file(path));
-        doc_tests.push(new_tests);
+        match extract_tests_from_file(&path, &name_path, test_unlabeled_blocks, html_code_blocks) {
+            Ok(ref new_tests) if require_tests && new_tests.tests.is_empty() => {
+                errors.push(SkepticError::Invalid(
+                    format!("{}: the document has no Rust examples, but \
+                             `require_tests_per_doc` is set",
+                            path.display())));
+            }
+            Ok(new_tests) => doc_tests.push(new_tests),
+            Err(e) => errors.push(e),
+        }
+    }
+    match errors.len() {
+        0 => {}
+        1 => return Err(errors.pop().unwrap()),
+        _ => return Err(SkepticError::Multiple(errors)),
     }
+    // The generated file only changes when the documents do, whichever
+    // order they are given in
+    doc_tests.sort_by(|a, b| a.path.cmp(&b.path));
     return Ok(DocTestSuite { doc_tests: doc_tests });
 }
 
-fn extract_tests_from_file(path: &Path) -> Result<DocTest, IoError> {
+/// The shortest end of the path of each document that tells its tests apart
+/// from those of the others, e.g. `a/guide.md` and `b/guide.md` for
+/// `docs/a/guide.md` and `docs/b/guide.md`, but just `faq.md` for
+/// `docs/faq.md`.
+fn name_paths(docs: &[String]) -> Vec<PathBuf> {
+    let components = docs.iter()
+        .map(|doc| {
+            Path::new(doc).components()
+                .filter_map(|c| match c {
+                    Component::Normal(c) => Some(c),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let name_path = |i: usize, len: usize| {
+        let components = &components[i];
+        components[components.len().saturating_sub(len)..].iter().collect::<PathBuf>()
+    };
+
+    let mut lens = vec![1; docs.len()];
+    loop {
+        let names = (0..docs.len()).map(|i| doc_name(&name_path(i, lens[i]))).collect::<Vec<_>>();
+        let mut lengthened = false;
+        for i in 0..docs.len() {
+            let ambiguous = names.iter().enumerate().any(|(j, name)| j != i && *name == names[i]);
+            if ambiguous && lens[i] < components[i].len() {
+                lens[i] += 1;
+                lengthened = true;
+            }
+        }
+        if !lengthened {
+            break;
+        }
+    }
+    (0..docs.len()).map(|i| name_path(i, lens[i])).collect()
+}
+
+/// Extracts the Rust code blocks of the markdown document at `path`, without
+/// generating any tests for them. Those of the doc comments of a Rust
+/// source file are extracted when its name ends with `.rs`.
+pub fn extract_tests_from_path(path: &Path) -> Result<Vec<ExtractedTest>, SkepticError> {
+    extract_tests_from_file(path, file_name(path)?, false, false).map(|doc_test| doc_test.tests)
+}
+
+/// The file name of `path`, which the tests of the document there are named
+/// after.
+fn file_name(path: &Path) -> Result<&Path, SkepticError> {
+    path.file_name()
+        .map(Path::new)
+        .ok_or_else(|| SkepticError::Invalid(format!("{} has no file name", path.display())))
+}
+
+/// Extracts the Rust code blocks of the `///` and `//!` doc comments of the
+/// Rust source file at `path`, without generating any tests for them. The
+/// lines of the examples are those of the source file. Like
+/// `extract_tests_from_path`, it returns `ExtractedTest`s, since the
+/// document they are collected in is internal, and a `SkepticError`, since
+/// a malformed example is an error as much as a file that can't be read.
+pub fn extract_tests_from_rust_source(path: &Path) -> Result<Vec<ExtractedTest>, SkepticError> {
+    let name_path = file_name(path)?;
+    let s = &doc_comments(&read_file(path)?);
+    extract_tests_from_markdown(path, s, name_path, false, false).map(|doc_test| doc_test.tests)
+}
+
+/// Extracts the tests of the document at `path`, or of the doc comments of
+/// the file if it's Rust source. When `test_unlabeled_blocks` is set, code
+/// blocks without an info string are Rust, as for rustdoc. When
+/// `html_code_blocks` is set, so are the `<pre><code class="language-rust">`
+/// sections of HTML blocks.
+fn extract_tests_from_file(path: &Path,
+                           name_path: &Path,
+                           test_unlabeled_blocks: bool,
+                           html_code_blocks: bool)
+                           -> Result<DocTest, SkepticError> {
+    let contents = read_file(path)?;
+    extract_tests_from_contents(path, &contents, name_path, test_unlabeled_blocks, html_code_blocks)
+}
+
+/// Extracts the tests of `contents`, the contents of the document or Rust
+/// source file at `path`.
+fn extract_tests_from_contents(path: &Path,
+                               contents: &str,
+                               name_path: &Path,
+                               test_unlabeled_blocks: bool,
+                               html_code_blocks: bool)
+                               -> Result<DocTest, SkepticError> {
+    let (ref s, mode) = if path.extension().map(|extension| extension == "rs").unwrap_or(false) {
+        (doc_comments(contents), None)
+    } else {
+        (strip_front_matter(contents), front_matter_mode(path, contents)?)
+    };
+    let mut doc_test = extract_tests_from_markdown(path,
+                                                   s,
+                                                   name_path,
+                                                   test_unlabeled_blocks,
+                                                   html_code_blocks)?;
+    doc_test.default_mode = mode;
+    Ok(doc_test)
+}
+
+/// Extracts the tests of `s`, the markdown of the document at `path`.
+fn extract_tests_from_markdown(path: &Path,
+                               s: &str,
+                               name_path: &Path,
+                               test_unlabeled_blocks: bool,
+                               html_code_blocks: bool)
+                               -> Result<DocTest, SkepticError> {
     let mut tests = Vec::new();
     // Oh this isn't actually a test but a legacy template
     let mut old_template = None;
+    let mut inline_templates = HashMap::new();
+    let mut default_edition = None;
+    let mut inputs = Vec::new();
 
-    let mut file = try!(File::open(path));
-    let ref mut s = String::new();
-    try!(file.read_to_string(s));
-    let parser = Parser::new(s);
+    let events = DocEvents::new(s, html_code_blocks);
 
-    let mut test_name_gen = TestNameGen::new(path);
+    let mut test_name_gen = TestNameGen::new(name_path);
+    // The line of the example each test name is taken by
+    let mut name_lines = HashMap::new();
     let mut code_buffer = None;
+    let mut line = 0;
+    // The tags of the last test whose output is in the next code blocks,
+    // `expect_output` first, then `expect_stderr`.
+    let mut awaiting_output = Vec::new();
+    // The output of an `expect_output` example may be in an unlabeled block
+    let parse_info = |info: &str, awaiting_output: bool| {
+        let mut code_block_info = parse_code_block_info(info);
+        if test_unlabeled_blocks && !awaiting_output && info.trim().is_empty() {
+            code_block_info.is_rust = true;
+        }
+        code_block_info
+    };
+    let missing_output = |test: &ExtractedTest, tag: &str| {
+        SkepticError::Invalid(format!("{}:{}: test {} is marked `{}`, but isn't \
+                                       followed by a code block with its output",
+                                      path.display(), test.line, test.name, tag))
+    };
 
-    for event in parser {
+    for (event, event_line) in events {
         match event {
             Event::Start(Tag::CodeBlock(ref info)) => {
-                let code_block_info = parse_code_block_info(info);
-                if code_block_info.is_rust {
-                    code_buffer = Some(Vec::new());
+                let code_block_info = parse_info(info, !awaiting_output.is_empty());
+                if let Some(&tag) = awaiting_output.first() {
+                    if code_block_info.is_rust {
+                        return Err(missing_output(tests.last().unwrap(), tag));
+                    }
+                }
+                if code_block_info.is_rust || !awaiting_output.is_empty() {
+                    code_buffer = Some(String::new());
+                    line = event_line;
                 }
             }
             Event::Text(text) => {
                 if let Some(ref mut buf) = code_buffer {
-                    buf.push(text.to_string());
+                    buf.push_str(&text);
+                }
+            }
+            Event::End(Tag::CodeBlock(_)) if !awaiting_output.is_empty() => {
+                if let Some(buf) = code_buffer.take() {
+                    let test = tests.last_mut().unwrap();
+                    match awaiting_output.remove(0) {
+                        "expect_output" => test.expected_output = Some(buf),
+                        _ => test.expected_stderr = Some(buf),
+                    }
                 }
             }
             Event::End(Tag::CodeBlock(ref info)) => {
-                let code_block_info = parse_code_block_info(info);
+                let code_block_info = parse_info(info, false);
                 if let Some(buf) = code_buffer.take() {
                     if code_block_info.is_old_template {
-                        old_template = Some(buf.into_iter().collect())
+                        old_template = Some(buf)
+                    } else if let Some(t) = code_block_info.defines_template {
+                        inline_templates.insert(t, buf);
                     } else {
-                        tests.push(Test {
-                            name: test_name_gen.advance(),
-                            text: buf,
+                        let name = test_name_gen.advance(code_block_info.name.as_ref());
+                        if let Some(other_line) = name_lines.insert(name.clone(), line) {
+                            return Err(SkepticError::Invalid(
+                                format!("{}:{}: test {} has the same name as the example on \
+                                         line {}",
+                                        path.display(), line, name, other_line)));
+                        }
+                        if code_block_info.compile_fail && code_block_info.should_panic {
+                            return Err(SkepticError::Invalid(
+                                format!("{}:{}: test {} is marked both `compile_fail` and \
+                                         `should_panic`, which is meaningless",
+                                        path.display(), line, name)));
+                        }
+                        if code_block_info.no_run && code_block_info.should_panic {
+                            return Err(SkepticError::Invalid(
+                                format!("{}:{}: test {} is marked both `no_run` and \
+                                         `should_panic`, which is contradictory since it \
+                                         is never run. Examples that must not compile \
+                                         are marked `compile_fail`",
+                                        path.display(), line, name)));
+                        }
+                        if code_block_info.check_only &&
+                           (code_block_info.should_panic || code_block_info.compile_fail) {
+                            let tag = if code_block_info.should_panic {
+                                "should_panic"
+                            } else {
+                                "compile_fail"
+                            };
+                            return Err(SkepticError::Invalid(
+                                format!("{}:{}: test {} is marked both `check_only` and `{}`, \
+                                         but an example that is only type-checked is \
+                                         expected to compile and is never run",
+                                        path.display(), line, name, tag)));
+                        }
+                        let exit_code = match code_block_info.exit_code {
+                            Some(ref code) => {
+                                let never_run = code_block_info.no_run ||
+                                                code_block_info.compile_fail ||
+                                                code_block_info.check_only;
+                                if never_run || code_block_info.should_panic {
+                                    return Err(SkepticError::Invalid(
+                                        format!("{}:{}: test {} has `exit_code={}`, but {}",
+                                                path.display(), line, name, code,
+                                                if never_run {
+                                                    "isn't run"
+                                                } else {
+                                                    "is expected to panic"
+                                                })));
+                                }
+                                match code.parse::<i32>() {
+                                    Ok(code) => Some(code),
+                                    Err(_) => {
+                                        return Err(SkepticError::Invalid(
+                                            format!("{}:{}: test {} has `exit_code={}`, which \
+                                                     isn't a number",
+                                                    path.display(), line, name, code)));
+                                    }
+                                }
+                            }
+                            None => None,
+                        };
+                        if code_block_info.panic_abort && code_block_info.should_panic {
+                            return Err(SkepticError::Invalid(
+                                format!("{}:{}: test {} is marked both `panic_abort` and \
+                                         `should_panic`, but an example compiled with \
+                                         `-C panic=abort` can't unwind from its panic",
+                                        path.display(), line, name)));
+                        }
+                        if code_block_info.expect_output {
+                            awaiting_output.push("expect_output");
+                        }
+                        if code_block_info.expect_stderr {
+                            awaiting_output.push("expect_stderr");
+                        }
+                        if let Some(&tag) = awaiting_output.first() {
+                            if code_block_info.no_run || code_block_info.compile_fail ||
+                               code_block_info.check_only {
+                                return Err(SkepticError::Invalid(
+                                    format!("{}: test {} is marked `{}`, but isn't run",
+                                            path.display(), name, tag)));
+                            }
+                        }
+                        if let Some(edition) = code_block_info.default_edition {
+                            if !tests.is_empty() {
+                                return Err(SkepticError::Invalid(
+                                    format!("{}:{}: test {} has `default-edition{}`, but only \
+                                             the first example of a document can set its \
+                                             default edition",
+                                            path.display(), line, name, edition)));
+                            }
+                            default_edition = Some(edition);
+                        }
+                        match code_block_info.cwd {
+                            Some(ref cwd) if cwd != "manifest" => {
+                                return Err(SkepticError::Invalid(
+                                    format!("{}:{}: test {} has `cwd={}`, but the only \
+                                             directory examples can be run in is \
+                                             `cwd=manifest`",
+                                            path.display(), line, name, cwd)));
+                            }
+                            _ => {}
+                        }
+                        let mut aux_files = Vec::new();
+                        for aux in &code_block_info.aux_files {
+                            let aux_path = path.with_file_name(aux);
+                            if !aux_path.is_file() {
+                                return Err(SkepticError::Invalid(
+                                    format!("{}:{}: test {} has `aux={}`, but {} isn't a file",
+                                            path.display(), line, name, aux,
+                                            aux_path.display())));
+                            }
+                            aux_files.push(aux_path);
+                        }
+                        inputs.extend(aux_files.iter().cloned());
+                        let text = expand_includes(path, line, &buf, &mut inputs)?;
+                        let segments = split_segments(&text);
+                        if segments.is_some() && !awaiting_output.is_empty() {
+                            return Err(SkepticError::Invalid(
+                                format!("{}:{}: test {} is split with `{}`, so it has no \
+                                         single output for `{}` to check",
+                                        path.display(), line, name, SEGMENT_DELIMITER,
+                                        awaiting_output[0])));
+                        }
+                        let test = ExtractedTest {
+                            name,
+                            text,
+                            line,
                             ignore: code_block_info.ignore,
+                            ignore_reason: code_block_info.ignore_reason,
                             no_run: code_block_info.no_run,
                             should_panic: code_block_info.should_panic,
+                            expected_panic: code_block_info.expected_panic,
+                            compile_fail: code_block_info.compile_fail,
                             template: code_block_info.template,
-                        });
+                            edition: code_block_info.edition,
+                            expected_output: None,
+                            expected_stderr: None,
+                            externs: code_block_info.externs,
+                            error_codes: code_block_info.error_codes,
+                            cwd_manifest: code_block_info.cwd.is_some(),
+                            required_features: code_block_info.required_features,
+                            no_miri: code_block_info.no_miri,
+                            check: code_block_info.check,
+                            panic_abort: code_block_info.panic_abort,
+                            aux_files,
+                            check_only: code_block_info.check_only,
+                            exit_code,
+                        };
+                        let segments = match segments {
+                            Some(segments) => segments,
+                            None => {
+                                tests.push(test);
+                                continue;
+                            }
+                        };
+                        for (i, segment) in segments.into_iter().enumerate() {
+                            let name = format!("{}_{}", test.name, i + 1);
+                            if let Some(other_line) = name_lines.insert(name.clone(), line) {
+                                return Err(SkepticError::Invalid(
+                                    format!("{}:{}: test {} has the same name as the \
+                                             example on line {}",
+                                            path.display(), line, name, other_line)));
+                            }
+                            tests.push(ExtractedTest {
+                                name,
+                                text: segment,
+                                ..test.clone()
+                            });
+                        }
                     }
                 }
             }
             _ => (),
         }
     }
+    if let Some(&tag) = awaiting_output.first() {
+        return Err(missing_output(tests.last().unwrap(), tag));
+    }
 
-    let templates = load_templates(path)?;
+    // Templates defined in the document itself take precedence over those
+    // of the same name in its template file.
+    let mut templates = load_templates(path)?;
+    templates.extend(inline_templates);
 
     Ok(DocTest {
         path: path.to_owned(),
+        name_path: name_path.to_owned(),
         old_template: old_template,
+        default_edition,
+        default_mode: None,
         tests: tests,
         templates: templates,
+        inputs,
     })
 }
 
-fn load_templates(path: &Path) -> Result<HashMap<String, String>, IoError> {
-    let file_name = format!("{}.skt.md", path.file_name().expect("no file name").to_string_lossy());
-    let path = path.with_file_name(&file_name);
-    if !path.exists() {
-        return Ok(HashMap::new());
+/// Replaces the `{{#include <path>}}` lines of the code of an example, which
+/// starts on `line` of the document at `doc_path`, with the contents of the
+/// file at `<path>`, relative to the document. When the directive is hidden
+/// with `# `, so are the lines included. The included files are added to
+/// `included`.
+fn expand_includes(doc_path: &Path,
+                   line: usize,
+                   code: &str,
+                   included: &mut Vec<PathBuf>)
+                   -> Result<String, SkepticError> {
+    let mut out = String::with_capacity(code.len());
+    for (i, code_line) in code.split_inclusive('\n').enumerate() {
+        let trimmed = code_line.trim();
+        let (hidden, directive) = match trimmed.strip_prefix("# ") {
+            Some(rest) => (true, rest.trim_start()),
+            None => (false, trimmed),
+        };
+        let include = directive.strip_prefix("{{#include ").and_then(|d| d.strip_suffix("}}"));
+        let include = match include {
+            Some(include) => doc_path.with_file_name(include.trim()),
+            None => {
+                out.push_str(code_line);
+                continue;
+            }
+        };
+
+        let contents = fs::read_to_string(&include).map_err(|e| {
+            SkepticError::Invalid(format!("{}:{}: failed to include {}: {}",
+                                          doc_path.display(), line + i, include.display(), e))
+        })?;
+        let contents = contents.strip_prefix(BOM).unwrap_or(&contents);
+        included.push(include);
+        for included_line in contents.split_inclusive('\n') {
+            if hidden {
+                out.push_str("# ");
+            }
+            out.push_str(included_line);
+        }
+        if !out.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+    Ok(out)
+}
+
+/// The line splitting the code of an example into a setup shared by the
+/// tests that follow each of these lines.
+const SEGMENT_DELIMITER: &str = "// ---";
+
+/// Splits the code of an example at its `// ---` lines, or `None` if it has
+/// none. Each segment after a delimiter is the code of a test of its own,
+/// following the code before the first delimiter. The lines of the other
+/// segments are left blank, so that the segment stays on the same lines.
+fn split_segments(code: &str) -> Option<Vec<String>> {
+    let mut setup = String::new();
+    let mut segments: Vec<String> = Vec::new();
+    // The lines of the delimiters and segments so far, left blank
+    let mut skipped = 0;
+    for code_line in code.split_inclusive('\n') {
+        skipped += 1;
+        if code_line.trim() == SEGMENT_DELIMITER {
+            segments.push(format!("{}{}", setup, "\n".repeat(skipped)));
+        } else {
+            match segments.last_mut() {
+                Some(segment) => segment.push_str(code_line),
+                None => {
+                    setup.push_str(code_line);
+                    skipped = 0;
+                }
+            }
+        }
+    }
+    if segments.is_empty() {
+        None
+    } else {
+        Some(segments)
+    }
+}
+
+/// The markdown of the `///` and `//!` doc comments of Rust source, without
+/// the comment markers and the space following them. Every other line is
+/// blank, so that the examples stay on the same lines.
+fn doc_comments(source: &str) -> String {
+    let mut out = String::with_capacity(source.len());
+    for line in source.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        let comment = if trimmed.starts_with("////") {
+            None
+        } else {
+            trimmed.strip_prefix("///").or_else(|| trimmed.strip_prefix("//!"))
+        };
+        if let Some(comment) = comment {
+            let comment = comment.strip_prefix(' ').unwrap_or(comment);
+            out.push_str(comment.trim_end_matches(['\n', '\r']));
+        }
+        if line.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Blanks out the YAML front matter between `---` lines, or TOML front
+/// matter between `+++` lines, at the start of a document, so it isn't
+/// parsed as markdown. Its lines are kept, so that the examples stay on the
+/// same lines.
+fn strip_front_matter(s: &str) -> String {
+    match front_matter(s) {
+        Some((lines, end)) => format!("{}{}", "\n".repeat(lines.len() + 2), &s[end..]),
+        None => s.to_string(),
+    }
+}
+
+/// The lines of the front matter at the start of a document, between its
+/// fences, and its length, fences included.
+fn front_matter(s: &str) -> Option<(Vec<&str>, usize)> {
+    let mut lines = s.split_inclusive('\n');
+    let first = lines.next().unwrap_or("");
+    let fence = first.trim_end();
+    if fence != "---" && fence != "+++" {
+        return None;
+    }
+    let mut end = first.len();
+    let mut inner = Vec::new();
+    for line in lines {
+        end += line.len();
+        if line.trim_end() == fence {
+            return Some((inner, end));
+        }
+        inner.push(line);
+    }
+    // Without a closing fence, it's not front matter
+    None
+}
+
+/// The mode the examples of the document at `path`, whose contents are `s`,
+/// default to, from a `skeptic: no_run` or `skeptic: ignore` line of its
+/// YAML front matter, or `skeptic = "no_run"` in TOML.
+fn front_matter_mode(path: &Path, s: &str) -> Result<Option<String>, SkepticError> {
+    let lines = match front_matter(s) {
+        Some((lines, _)) => lines,
+        None => return Ok(None),
+    };
+    for (i, line) in lines.iter().enumerate() {
+        let value = match line.strip_prefix("skeptic") {
+            Some(rest) => rest.trim_start(),
+            None => continue,
+        };
+        let value = match value.strip_prefix(':').or_else(|| value.strip_prefix('=')) {
+            Some(value) => value.trim().trim_matches(|c| c == '"' || c == '\''),
+            None => continue,
+        };
+        return match value {
+            "ignore" | "no_run" => Ok(Some(value.to_string())),
+            _ => {
+                Err(SkepticError::Invalid(
+                    format!("{}:{}: the front matter sets `skeptic` to `{}`, but examples can \
+                             only default to `ignore` or `no_run`",
+                            path.display(), i + 2, value)))
+            }
+        };
+    }
+    Ok(None)
+}
+
+/// Reads the file at `path`, without the byte order mark some editors start
+/// UTF-8 files with, which would otherwise be part of its first line.
+fn read_file(path: &Path) -> Result<String, SkepticError> {
+    let mut s = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut s))
+        .map_err(|e| SkepticError::io(path, e))?;
+    match s.strip_prefix(BOM) {
+        Some(rest) => Ok(rest.to_string()),
+        None => Ok(s),
+    }
+}
+
+const BOM: char = '\u{feff}';
+
+/// The names the template file of the document at `path` may have, e.g.
+/// `guide.markdown.skt.md`, `guide.skt.md` and `guide.skt.markdown`, in the
+/// order they are looked up.
+fn template_paths(path: &Path) -> Vec<PathBuf> {
+    let file_name = path.file_name().expect("no file name").to_string_lossy();
+    let stem = path.file_stem().expect("no file name").to_string_lossy();
+    let mut names = vec![format!("{}.skt.md", file_name), format!("{}.skt.md", stem)];
+    if let Some(extension) = path.extension() {
+        names.push(format!("{}.skt.{}", stem, extension.to_string_lossy()));
     }
+    names.dedup();
+    names.into_iter().map(|name| path.with_file_name(name)).collect()
+}
+
+fn load_templates(path: &Path) -> Result<HashMap<String, String>, SkepticError> {
+    let path = match template_paths(path).into_iter().find(|path| path.exists()) {
+        Some(path) => path,
+        None => return Ok(HashMap::new()),
+    };
 
     let mut map = HashMap::new();
+    // The line each template is defined on
+    let mut lines = HashMap::new();
 
-    let mut file = try!(File::open(path));
-    let ref mut s = String::new();
-    try!(file.read_to_string(s));
-    let parser = Parser::new(s);
+    let s = &read_file(&path)?;
+    let mut parser = Parser::new(s);
 
     let mut code_buffer = None;
+    let mut line_counter = LineCounter::default();
+    let mut line = 0;
 
-    for event in parser {
+    while let Some(event) = parser.next() {
         match event {
             Event::Start(Tag::CodeBlock(ref info)) => {
                 let code_block_info = parse_code_block_info(info);
                 if code_block_info.is_rust {
-                    code_buffer = Some(Vec::new());
+                    code_buffer = Some(String::new());
+                    line = line_counter.line_at(s, parser.get_offset());
                 }
             }
             Event::Text(text) => {
                 if let Some(ref mut buf) = code_buffer {
-                    buf.push(text.to_string());
+                    buf.push_str(&text);
                 }
             }
             Event::End(Tag::CodeBlock(ref info)) => {
                 let code_block_info = parse_code_block_info(info);
                 if let Some(buf) = code_buffer.take() {
                     if let Some(t) = code_block_info.template {
-                        map.insert(t, buf.into_iter().collect());
+                        // The second definition would silently replace the first
+                        if let Some(other_line) = lines.insert(t.clone(), line) {
+                            return Err(SkepticError::Invalid(
+                                format!("{}:{}: template `skt-{}` is already defined on line {}",
+                                        path.display(), line, t, other_line)));
+                        }
+                        map.insert(t, buf);
                     }
                 }
             }
@@ -187,138 +1399,900 @@ fn load_templates(path: &Path) -> Result<HashMap<String, String>, IoError> {
     Ok(map)
 }
 
-struct TestNameGen {
-    root: String,
-    count: i32,
+/// Finds the lines of increasing offsets of a document, counting each
+/// newline once, however many examples the document has.
+#[derive(Default)]
+struct LineCounter {
+    offset: usize,
+    newlines: usize,
 }
 
-impl TestNameGen {
-    fn new(path: &Path) -> TestNameGen {
-        let ref file_stem = path.file_stem().unwrap().to_str().unwrap().to_string();
-        TestNameGen {
-            root: sanitize_test_name(file_stem),
-            count: 0,
-        }
+impl LineCounter {
+    /// The line of `s` the byte at `offset` is on, starting from 1.
+    /// `offset` is no less than the one it was last given.
+    fn line_at(&mut self, s: &str, offset: usize) -> usize {
+        self.newlines += s[self.offset..offset].matches('\n').count();
+        self.offset = offset;
+        self.newlines + 1
     }
+}
 
-    fn advance(&mut self) -> String {
-        let count = self.count;
-        self.count += 1;
-        format!("{}_{}", self.root, count)
-    }
+/// The events of the markdown of a document, each with the line it ends
+/// on, or the line the code of a code block starts on. When
+/// `html_code_blocks` is set, the `<pre><code>` sections of HTML blocks are
+/// turned into the code blocks they display.
+struct DocEvents<'a> {
+    s: &'a str,
+    parser: Parser<'a>,
+    line_counter: LineCounter,
+    html_code_blocks: bool,
+    // The events of the code blocks found in HTML, and the event after it
+    pending: VecDeque<(Event<'a>, usize)>,
 }
 
-fn sanitize_test_name(s: &str) -> String {
-    to_lowercase(s)
-        .chars()
-        .map(|c| {
-            if c.is_alphanumeric() {
+impl<'a> DocEvents<'a> {
+    fn new(s: &'a str, html_code_blocks: bool) -> DocEvents<'a> {
+        DocEvents {
+            s,
+            parser: Parser::new(s),
+            line_counter: LineCounter::default(),
+            html_code_blocks,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn next_event(&mut self) -> Option<(Event<'a>, usize)> {
+        let event = self.parser.next()?;
+        Some((event, self.line_counter.line_at(self.s, self.parser.get_offset())))
+    }
+
+    /// The line the `html` of the event just parsed starts on, the first
+    /// of its lines after `before`, the offset the event before it ended
+    /// at. The offset an HTML block ends at can be past the blank lines
+    /// following it, so the line can't be counted back from it.
+    fn html_line(&self, before: usize, html: &str) -> usize {
+        let end = self.line_counter.offset;
+        let first_line = html.split('\n').next().unwrap_or("");
+        let start = self.s[before..end].find(first_line).map_or(before, |i| before + i);
+        self.line_counter.newlines + 1 - self.s[start..end].matches('\n').count()
+    }
+}
+
+impl<'a> Iterator for DocEvents<'a> {
+    type Item = (Event<'a>, usize);
+
+    fn next(&mut self) -> Option<(Event<'a>, usize)> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+        let before = self.line_counter.offset;
+        let html = match self.next_event()? {
+            (Event::Html(html), _) if self.html_code_blocks => html,
+            event => return Some(event),
+        };
+        // An HTML block may be split in several events, which are joined,
+        // with the offset in the joined HTML and the line of each
+        let mut pieces = vec![(0, self.html_line(before, &html))];
+        let mut joined = html.into_owned();
+        let next = loop {
+            let before = self.line_counter.offset;
+            match self.next_event() {
+                Some((Event::Html(html), _)) => {
+                    pieces.push((joined.len(), self.html_line(before, &html)));
+                    joined.push_str(&html);
+                }
+                event => break event,
+            }
+        };
+        for (offset, info, code) in html_code_blocks(&joined) {
+            let &(piece_offset, piece_line) = pieces.iter()
+                .rev()
+                .find(|&&(piece_offset, _)| piece_offset <= offset)
+                .unwrap();
+            let line = piece_line + joined[piece_offset..offset].matches('\n').count();
+            let info: Cow<str> = Cow::Owned(info);
+            self.pending.push_back((Event::Start(Tag::CodeBlock(info.clone())), line));
+            self.pending.push_back((Event::Text(Cow::Owned(code)), line));
+            self.pending.push_back((Event::End(Tag::CodeBlock(info)), line));
+        }
+        self.pending.extend(next);
+        self.next()
+    }
+}
+
+/// The `<pre><code>` sections of `html`, with the offset their code starts
+/// at, the info string of a code block with their classes, and their code
+/// without its tags and with its entities decoded. `language-rust` is
+/// `rust` in the info string, and sections without a `language-` class are
+/// `text`, so that they are never Rust, but can have the output of an
+/// example.
+fn html_code_blocks(html: &str) -> Vec<(usize, String, String)> {
+    // Tag names are case insensitive, and lowercasing ASCII keeps offsets
+    let lower = html.to_ascii_lowercase();
+    let tag_end = |from: usize| lower[from..].find('>').map(|i| from + i + 1);
+    let is_tag = |at: usize, name: &str| {
+        lower[at..].starts_with(name) &&
+        lower[at + name.len()..].starts_with(|c: char| c == '>' || c.is_whitespace())
+    };
+
+    let mut blocks = Vec::new();
+    let mut from = 0;
+    while let Some(i) = lower[from..].find("<pre") {
+        let pre = from + i;
+        from = pre + 1;
+        if !is_tag(pre, "<pre") {
+            continue;
+        }
+        let pre_end = match tag_end(pre) {
+            Some(pre_end) => pre_end,
+            None => break,
+        };
+        let code_tag = pre_end + (lower[pre_end..].len() - lower[pre_end..].trim_start().len());
+        if !is_tag(code_tag, "<code") {
+            continue;
+        }
+        let code_start = match tag_end(code_tag) {
+            Some(code_start) => code_start,
+            None => break,
+        };
+        let code_end = match lower[code_start..].find("</code>") {
+            Some(i) => code_start + i,
+            None => break,
+        };
+        from = code_end;
+
+        let classes = html_class(&html[code_tag..code_start]);
+        let mut language = None;
+        let mut tags = Vec::new();
+        for class in classes.split_whitespace() {
+            match class.strip_prefix("language-") {
+                Some(l) if language.is_none() => language = Some(l),
+                _ => tags.push(class),
+            }
+        }
+        tags.insert(0, language.unwrap_or("text"));
+        let code = decode_html_entities(&strip_html_tags(&html[code_start..code_end]));
+        blocks.push((code_start, tags.join(","), code));
+    }
+    blocks
+}
+
+/// The value of the `class` attribute of an HTML start tag, or an empty
+/// string if it has none.
+fn html_class(tag: &str) -> &str {
+    let lower = tag.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(i) = lower[from..].find("class") {
+        let at = from + i;
+        from = at + 1;
+        if !lower[..at].ends_with(char::is_whitespace) {
+            continue;
+        }
+        let value = lower[at + "class".len()..].trim_start();
+        if !value.starts_with('=') {
+            continue;
+        }
+        let value = value[1..].trim_start();
+        let start = tag.len() - value.len();
+        return match value.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => {
+                let value = &tag[start + 1..];
+                &value[..value.find(quote).unwrap_or(value.len())]
+            }
+            _ => {
+                let value = &tag[start..];
+                let end = value.find(|c: char| c == '>' || c.is_whitespace());
+                &value[..end.unwrap_or(value.len())]
+            }
+        };
+    }
+    ""
+}
+
+/// `html` without its tags, like the `<span>`s of highlighted code, whose
+/// `<` must otherwise be escaped.
+fn strip_html_tags(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(i) = rest.find('<') {
+        out.push_str(&rest[..i]);
+        rest = match rest[i..].find('>') {
+            Some(j) => &rest[i + j + 1..],
+            None => "",
+        };
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Decodes the entities of `html` that code can contain: the escapes of
+/// `<`, `>`, `&`, `"` and `'`, `&nbsp;`, and numeric character references.
+/// Other entities are left as they are.
+fn decode_html_entities(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let entity = rest.find(';').map(|end| &rest[1..end]);
+        let decoded = entity.and_then(|entity| {
+            match entity {
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "amp" => Some('&'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                _ => {
+                    let number = entity.strip_prefix('#')?;
+                    let code = match number.strip_prefix('x').or_else(|| number.strip_prefix('X')) {
+                        Some(hex) => u32::from_str_radix(hex, 16),
+                        None => number.parse(),
+                    };
+                    code.ok().and_then(std::char::from_u32)
+                }
+            }
+        });
+        match (entity, decoded) {
+            (Some(entity), Some(c)) => {
+                out.push(c);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+struct TestNameGen {
+    root: String,
+    count: i32,
+}
+
+impl TestNameGen {
+    fn new(name_path: &Path) -> TestNameGen {
+        TestNameGen {
+            root: doc_name(name_path),
+            count: 0,
+        }
+    }
+
+    /// The name of the next test, from the name given to its example if
+    /// any. Examples are numbered whether they are named or not, so naming
+    /// one doesn't change the names of the others.
+    fn advance(&mut self, name: Option<&String>) -> String {
+        let count = self.count;
+        self.count += 1;
+        match name {
+            Some(name) => sanitize_test_name(&format!("{}_{}", self.root, name)),
+            None => format!("{}_{}", self.root, count),
+        }
+    }
+}
+
+/// The name the tests of a document are prefixed with, from the end of its
+/// path, e.g. `a_guide` for `a/guide.md`.
+fn doc_name(name_path: &Path) -> String {
+    sanitize_test_name(&name_path.with_extension("").to_string_lossy())
+}
+
+fn sanitize_test_name(s: &str) -> String {
+    // Only converting test names to lowercase to avoid style lints
+    // against test functions.
+    let mut name: String = s.chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| {
+            if UnicodeXID::is_xid_continue(c) {
                 c
             } else {
                 '_'
             }
         })
-        .collect()
-}
+        .collect();
+
+    // Identifiers can't start with a digit
+    if !name.starts_with(|c: char| c == '_' || UnicodeXID::is_xid_start(c)) {
+        name.insert(0, '_');
+    }
 
-// Only converting test names to lowercase to avoid style lints
-// against test functions.
-fn to_lowercase(s: &str) -> String {
-    use std::ascii::AsciiExt;
-    // FIXME: unicode
-    s.to_ascii_lowercase()
+    name
 }
 
+/// Parses the info string of a code block. Only blocks with an explicit
+/// `rust` or `rs` token are Rust, whatever else their info string says:
+///
+/// | info string   | Rust |
+/// |---------------|------|
+/// | `rust`        | yes  |
+/// | `rust,ignore` | yes  |
+/// | `rust,json`   | yes  |
+/// | `json`        | no   |
+/// | `ignore`      | no   |
+/// | (empty)       | no   |
+///
+/// Unlike rustdoc, which tests blocks without a language, skeptic leaves
+/// the prose of documents alone unless asked otherwise, so blocks with an
+/// empty info string are only tested with
+/// `SkepticBuilder::test_unlabeled_blocks`, and those with only attributes
+/// like `ignore` never are.
 fn parse_code_block_info(info: &str) -> CodeBlockInfo {
-    // Same as rustdoc
-    let tokens = info.split(|c: char| !(c == '_' || c == '-' || c.is_alphanumeric()));
+    let tokens = tokenize_info(info);
 
-    let mut seen_rust_tags = false;
-    let mut seen_other_tags = false;
     let mut info = CodeBlockInfo {
         is_rust: false,
         should_panic: false,
+        expected_panic: None,
         ignore: false,
+        ignore_reason: None,
         no_run: false,
+        compile_fail: false,
+        expect_output: false,
+        expect_stderr: false,
         is_old_template: false,
         template: None,
+        defines_template: None,
+        edition: None,
+        default_edition: None,
+        externs: Vec::new(),
+        error_codes: Vec::new(),
+        name: None,
+        cwd: None,
+        required_features: Vec::new(),
+        no_miri: false,
+        check: false,
+        panic_abort: false,
+        aux_files: Vec::new(),
+        check_only: false,
+        exit_code: None,
     };
 
-    for token in tokens {
+    for (token, payload) in tokens {
         match token {
-            "" => {}
-            "rust" => {
+            "rust" | "rs" => {
                 info.is_rust = true;
-                seen_rust_tags = true
             }
             "should_panic" => {
                 info.should_panic = true;
-                seen_rust_tags = true
+                info.expected_panic = payload.and_then(parse_expected_panic);
             }
             "ignore" => {
                 info.ignore = true;
-                seen_rust_tags = true
+                info.ignore_reason = payload.and_then(parse_ignore_reason);
             }
             "no_run" => {
                 info.no_run = true;
-                seen_rust_tags = true;
+            }
+            "compile_fail" => {
+                info.compile_fail = true;
+            }
+            "expect_output" => {
+                info.expect_output = true;
+            }
+            "expect_stderr" => {
+                info.expect_stderr = true;
+            }
+            "no_miri" => {
+                info.no_miri = true;
+            }
+            "check" => {
+                info.check = true;
+            }
+            "check_only" => {
+                info.check_only = true;
+            }
+            "panic_abort" => {
+                info.panic_abort = true;
+            }
+            "edition2015" | "edition2018" | "edition2021" | "edition2024" => {
+                info.edition = Some(token[7..].to_string());
+            }
+            "default-edition2015" | "default-edition2018" | "default-edition2021" |
+            "default-edition2024" => {
+                info.default_edition = Some(token[15..].to_string());
             }
             "skeptic-template" => {
                 info.is_old_template = true;
-                seen_rust_tags = true
+            }
+            _ if token.starts_with("skeptic-template-name=") => {
+                info.defines_template = Some(token["skeptic-template-name=".len()..].to_string());
+            }
+            _ if token.starts_with("requires-feature=") => {
+                info.required_features.push(token["requires-feature=".len()..].to_string());
+            }
+            _ if token.starts_with("cwd=") => {
+                info.cwd = Some(token["cwd=".len()..].to_string());
+            }
+            _ if token.starts_with("name=") => {
+                info.name = Some(token["name=".len()..].to_string());
+            }
+            _ if token.starts_with("extern=") => {
+                info.externs.push(token["extern=".len()..].to_string());
+            }
+            _ if token.starts_with("exit_code=") => {
+                info.exit_code = Some(token["exit_code=".len()..].to_string());
+            }
+            _ if token.starts_with("aux=") => {
+                info.aux_files.push(token["aux=".len()..].to_string());
             }
             _ if token.starts_with("skt-") => {
                 info.template = Some(token[4..].to_string());
-                seen_rust_tags = true;
             }
-            _ => seen_other_tags = true,
+            _ if is_error_code(token) => {
+                info.error_codes.push(token.to_string());
+            }
+            // Other languages, and attributes skeptic doesn't know
+            _ => {}
         }
     }
 
-    info.is_rust &= !seen_other_tags || seen_rust_tags;
-
     info
 }
 
+/// Whether a token is a rustc error code, like `E0308`.
+fn is_error_code(token: &str) -> bool {
+    token.len() == 5 && token.starts_with('E') && token[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+/// Splits an info string into its tokens, the same way as rustdoc, except
+/// that a parenthesized payload directly following a token, as in
+/// `should_panic(expected="boom")`, is kept and returned alongside it.
+/// Braces and dots separate tokens, so Pandoc's `{.rust .ignore}` has the
+/// tokens `rust` and `ignore`.
+fn tokenize_info(info: &str) -> Vec<(&str, Option<&str>)> {
+    // `=` is kept within tokens for `key=value` attributes, and the value
+    // may be a path, like that of `aux=`
+    let is_token_char = |c: char| c == '_' || c == '-' || c == '=' || c.is_alphanumeric();
+    let is_value_char = |c: char| c == '.' || c == '/' || is_token_char(c);
+
+    let mut tokens = Vec::new();
+    let mut rest = info;
+    while !rest.is_empty() {
+        let mut end = rest.find(|c: char| !is_token_char(c)).unwrap_or(rest.len());
+        if rest[..end].contains('=') {
+            end = rest.find(|c: char| !is_value_char(c)).unwrap_or(rest.len());
+        }
+        let token = &rest[..end];
+        rest = &rest[end..];
+
+        let mut payload = None;
+        if !token.is_empty() && rest.starts_with('(') {
+            // Find the matching paren, skipping over any quoted strings
+            let mut depth = 0;
+            let mut in_quotes = false;
+            let mut close = None;
+            for (i, c) in rest.char_indices() {
+                match c {
+                    '"' => in_quotes = !in_quotes,
+                    '(' if !in_quotes => depth += 1,
+                    ')' if !in_quotes => {
+                        depth -= 1;
+                        if depth == 0 {
+                            close = Some(i);
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(close) = close {
+                payload = Some(&rest[1..close]);
+                rest = &rest[close + 1..];
+            }
+        }
+
+        if !token.is_empty() {
+            tokens.push((token, payload));
+        }
+        if let Some(c) = rest.chars().next() {
+            if !is_token_char(c) {
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    tokens
+}
+
+/// Parses the `expected="..."` payload of `should_panic`. Everything between
+/// the first and the last quote is the expected message, so it may itself
+/// contain quotes.
+fn parse_expected_panic(payload: &str) -> Option<String> {
+    let payload = payload.trim();
+    if !payload.starts_with("expected") {
+        return None;
+    }
+    let value = payload["expected".len()..].trim_start();
+    if !value.starts_with('=') {
+        return None;
+    }
+    let value = value[1..].trim();
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        Some(value[1..value.len() - 1].to_string())
+    } else {
+        None
+    }
+}
+
+/// Parses the payload of `ignore`, the reason the test is ignored, which may
+/// or may not be quoted.
+fn parse_ignore_reason(payload: &str) -> Option<String> {
+    let reason = payload.trim();
+    let reason = if reason.len() >= 2 && reason.starts_with('"') && reason.ends_with('"') {
+        &reason[1..reason.len() - 1]
+    } else {
+        reason
+    };
+    if reason.is_empty() {
+        None
+    } else {
+        Some(reason.to_string())
+    }
+}
+
+#[derive(Debug, PartialEq)]
 struct CodeBlockInfo {
     is_rust: bool,
     should_panic: bool,
+    expected_panic: Option<String>,
     ignore: bool,
+    ignore_reason: Option<String>,
     no_run: bool,
+    compile_fail: bool,
+    expect_output: bool,
+    expect_stderr: bool,
     is_old_template: bool,
     template: Option<String>,
+    /// The name of the template this block defines, from `skeptic-template-name=`.
+    defines_template: Option<String>,
+    edition: Option<String>,
+    /// The edition of the document's examples without an edition tag.
+    default_edition: Option<String>,
+    externs: Vec<String>,
+    error_codes: Vec<String>,
+    /// The name the test is given by `name=`, instead of a number.
+    name: Option<String>,
+    /// The directory the example is run in, from `cwd=`.
+    cwd: Option<String>,
+    required_features: Vec<String>,
+    no_miri: bool,
+    check: bool,
+    panic_abort: bool,
+    /// The files given by `aux=`, relative to the document.
+    aux_files: Vec<String>,
+    check_only: bool,
+    /// The code given by `exit_code=`, which must be a number.
+    exit_code: Option<String>,
 }
 
-fn emit_tests(config: &Config, suite: DocTestSuite) -> Result<(), IoError> {
+/// Writes the tests of the suite, and returns how many there are.
+fn emit_tests(config: &Config, suite: DocTestSuite) -> Result<usize, SkepticError> {
     let mut out = String::new();
+    let count = suite.doc_tests.iter().map(|doc_test| doc_test.tests.len()).sum();
+    let mut all_tests = Vec::new();
+    let mut json_documents = Vec::new();
 
-    // Test cases use the api from skeptic::rt
-    out.push_str("extern crate skeptic;\n");
+    // A plain comment, since inner doc comments are an error where the file
+    // is usually included. Nothing in it changes between builds, so that
+    // the file is only rewritten when the tests change.
+    out.push_str("// @generated by skeptic from: ");
+    out.push_str(&config.docs.join(", "));
+    out.push_str("\n// Do not edit, it is regenerated by the build script when the documents change.\n\n");
 
-    for doc_test in suite.doc_tests {
+    // Test cases use the api from skeptic::rt, which the includer brings in
+    // when the tests are wrapped in a module
+    match config.wrap_module {
+        Some(ref name) => {
+            out.push_str(&format!("mod {} {{\n", name));
+            out.push_str("use super::skeptic;\n\n");
+        }
+        None => out.push_str("extern crate skeptic;\n"),
+    }
+
+    let default_template = match config.default_template {
+        Some(ref path) => Some((format!("default template {}", path.display()), read_file(path)?)),
+        None => None,
+    };
+
+    for (doc_index, doc_test) in suite.doc_tests.into_iter().enumerate() {
+        let doc_path = doc_test.path.strip_prefix(&config.root_dir).unwrap_or(&doc_test.path);
+        let in_module = config.module_per_doc && !doc_test.tests.is_empty();
+        let mut modules: Vec<String> = config.wrap_module.iter().cloned().collect();
+        if in_module {
+            modules.push(module_name(&doc_test.name_path));
+            out.push_str(&format!("mod {} {{\n", module_name(&doc_test.name_path)));
+            out.push_str("use super::skeptic;\n\n");
+        }
+        let mut tests = Vec::new();
         for test in &doc_test.tests {
-            let test_string = {
+            let test = ExtractedTest {
+                edition: test.edition.clone().or_else(|| doc_test.default_edition.clone()),
+                ..test.clone()
+            };
+            // The mode of the document only applies to examples without one
+            let own_mode = test.ignore || test.no_run || test.compile_fail || test.check_only ||
+                           test.should_panic || test.expected_output.is_some() ||
+                           test.expected_stderr.is_some();
+            let test = match doc_test.default_mode.as_ref().map(|mode| &mode[..]) {
+                Some("ignore") if !own_mode => {
+                    ExtractedTest {
+                        ignore: true,
+                        ignore_reason: Some(format!("ignored by the front matter of {}",
+                                                    doc_path.display())),
+                        ..test
+                    }
+                }
+                Some(_) if !own_mode => ExtractedTest { no_run: true, ..test },
+                _ => test,
+            };
+            let test = match config.filter {
+                Some(ref filter) if !test.ignore &&
+                                    !matches_filter(filter, &test.name, doc_path) => {
+                    ExtractedTest {
+                        ignore: true,
+                        ignore_reason: Some(format!("filtered out by SKEPTIC_FILTER={}", filter)),
+                        ..test
+                    }
+                }
+                _ => test,
+            };
+            let test_text = {
                 if let Some(ref t) = test.template {
-                    let template = doc_test.templates.get(t)
-                        .expect(&format!("template {} not found for {}", t, doc_test.path.display()));
-                    try!(create_test_runner(config, &Some(template.to_string()), test))
+                    let template = match doc_test.templates.get(t) {
+                        Some(template) => template,
+                        None => return Err(template_not_found(&doc_test, &test, t)),
+                    };
+                    let name = format!("`skt-{}` of {}", t, doc_test.path.display());
+                    create_test_text(config, Some((&name, template)), &test)?
                 } else {
-                    try!(create_test_runner(config, &doc_test.old_template, test))
+                    let name = format!("`skeptic-template` of {}", doc_test.path.display());
+                    let template = match doc_test.old_template {
+                        Some(ref t) => Some((&name[..], &t[..])),
+                        None => default_template.as_ref().map(|(n, t)| (&n[..], &t[..])),
+                    };
+                    create_test_text(config, template, &test)?
                 }
             };
-            out.push_str(&test_string);
+            let test_text = match config.transform {
+                Some(Transform(ref transform)) => transform(&test_text),
+                None => test_text,
+            };
+            all_tests.push(test.clone());
+            tests.push((test, test_text));
+        }
+
+        // The examples each test can be compiled along with, by index
+        let mut batch_of = vec![None; tests.len()];
+        let batches = if config.batch { batches(&tests) } else { Vec::new() };
+        for (batch_index, batch) in batches.into_iter().enumerate() {
+            let static_name = format!("SKEPTIC_BATCH_{}_{}", doc_index, batch_index);
+            out.push_str(&format!("static {}: &[(&str, &str)] = &[\n", static_name));
+            for (i, &test_index) in batch.iter().enumerate() {
+                let (ref test, ref test_text) = tests[test_index];
+                out.push_str(&format!("    ({:?}, {}),\n", test.name, raw_string_literal(test_text)));
+                batch_of[test_index] = Some((static_name.clone(), i));
+            }
+            out.push_str("];\n\n");
+        }
+
+        for ((test, test_text), batch) in tests.iter().zip(batch_of) {
+            let location = format!("{}:{}", doc_path.display(), test.line);
+            let batch = batch.as_ref().map(|&(ref name, i)| (&name[..], i));
+            out.push_str(&create_test_runner(config, test, test_text, &location, batch));
+        }
+        if config.json_manifest {
+            json_documents.push(json_document(doc_path, &modules, &tests));
+        }
+        if in_module {
+            out.push_str("}\n\n");
         }
     }
+    if config.wrap_module.is_some() {
+        out.push_str("}\n");
+    }
     write_if_contents_changed(&config.out_file, &out)
+        .map_err(|e| SkepticError::io(&config.out_file, e))?;
+    if config.json_manifest {
+        let path = config.out_file.with_extension("json");
+        write_if_contents_changed(&path, &json_manifest(&json_documents))
+            .map_err(|e| SkepticError::io(&path, e))?;
+    }
+    if env_flag("SKEPTIC_SUMMARY") {
+        println!("cargo:warning={}", summary(&all_tests));
+    }
+    Ok(count)
+}
+
+/// The JSON manifest of the generated tests, from the `json_document`s of
+/// the documents, which is:
+///
+/// ```text
+/// {
+///   "version": 1,
+///   "documents": [
+///     {
+///       "path": "README.md",
+///       "tests": [
+///         {
+///           "name": "readme_0",
+///           "path": "readme::readme_0",
+///           "line": 12,
+///           "ignore": false,
+///           "ignore_reason": null,
+///           "no_run": false,
+///           "should_panic": false,
+///           "compile_fail": false,
+///           "check": false,
+///           "panic_abort": false,
+///           "no_miri": false,
+///           "check_only": false,
+///           "exit_code": null,
+///           "edition": "2018",
+///           "template": null
+///         }
+///       ]
+///     }
+///   ]
+/// }
+/// ```
+///
+/// A document's `path` is relative to the root directory, with `/`
+/// separators. A test's `name` is that of its function, and its `path`
+/// that of the function in the generated file, through the modules it is
+/// in. `line` is the line of the document on which the code of the example
+/// starts. `ignore` is also true for tests filtered out by `SKEPTIC_FILTER`,
+/// with `ignore_reason` saying so. `edition`, `template`, `ignore_reason`
+/// and `exit_code` are `null` when not given. Fields are only ever added,
+/// and `version` is raised if any is removed or changes meaning.
+fn json_manifest(documents: &[String]) -> String {
+    format!("{{\n  \"version\": 1,\n  \"documents\": [{}\n  ]\n}}\n",
+            documents.join(","))
+}
+
+/// An element of the `documents` of the JSON manifest, for the tests of the
+/// document at `doc_path`, which are in the `modules` of the generated file.
+fn json_document(doc_path: &Path, modules: &[String], tests: &[(ExtractedTest, String)]) -> String {
+    let json_tests: Vec<String> = tests.iter().map(|(test, _)| {
+        let mut path = modules.to_vec();
+        path.push(test.name.clone());
+        let fields = [("name", json_string(&test.name)),
+                      ("path", json_string(&path.join("::"))),
+                      ("line", test.line.to_string()),
+                      ("ignore", test.ignore.to_string()),
+                      ("ignore_reason", json_option(&test.ignore_reason)),
+                      ("no_run", test.no_run.to_string()),
+                      ("should_panic", test.should_panic.to_string()),
+                      ("compile_fail", test.compile_fail.to_string()),
+                      ("check", test.check.to_string()),
+                      ("panic_abort", test.panic_abort.to_string()),
+                      ("no_miri", test.no_miri.to_string()),
+                      ("check_only", test.check_only.to_string()),
+                      ("exit_code", test.exit_code.map_or_else(|| String::from("null"),
+                                                               |code| code.to_string())),
+                      ("edition", json_option(&test.edition)),
+                      ("template", json_option(&test.template))];
+        let fields: Vec<String> = fields.iter()
+            .map(|&(key, ref value)| format!("\n          \"{}\": {}", key, value))
+            .collect();
+        format!("\n        {{{}\n        }}", fields.join(","))
+    }).collect();
+    let doc_path = doc_path.to_string_lossy().replace('\\', "/");
+    format!("\n    {{\n      \"path\": {},\n      \"tests\": [{}\n      ]\n    }}",
+            json_string(&doc_path), json_tests.join(","))
+}
+
+/// `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_option(s: &Option<String>) -> String {
+    s.as_ref().map_or_else(|| String::from("null"), |s| json_string(s))
+}
+
+/// A line counting the tests that run their example, those that only
+/// compile it, and those that are ignored, including filtered out ones.
+fn summary(tests: &[ExtractedTest]) -> String {
+    let ignored = tests.iter().filter(|test| test.ignore).count();
+    let compiled = tests.iter()
+        .filter(|test| !test.ignore && (test.no_run || test.compile_fail || test.check_only))
+        .count();
+    format!("skeptic: {} examples: {} run, {} compiled-only, {} ignored",
+            tests.len(), tests.len() - ignored - compiled, compiled, ignored)
+}
+
+/// Whether a test matches `SKEPTIC_FILTER`, which is either a substring of
+/// its name or document, or a glob matching either of them with `*` and `?`.
+fn matches_filter(filter: &str, test_name: &str, doc_path: &Path) -> bool {
+    let doc_path = doc_path.to_string_lossy();
+    if filter.contains(['*', '?']) {
+        glob_matches(filter, test_name) || glob_matches(filter, &doc_path)
+    } else {
+        test_name.contains(filter) || doc_path.contains(filter)
+    }
+}
+
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    // Where to resume after the last `*` when the rest fails to match
+    let mut backtrack = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(&'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, star_t)) => {
+                    backtrack = Some((star, star_t + 1));
+                    p = star + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn template_not_found(doc_test: &DocTest, test: &ExtractedTest, template: &str) -> SkepticError {
+    let mut available = doc_test.templates.keys().cloned().collect::<Vec<_>>();
+    available.sort();
+    SkepticError::TemplateNotFound {
+        path: doc_test.path.clone(),
+        line: test.line,
+        test: test.name.clone(),
+        template: template.to_string(),
+        available,
+    }
+}
+
+/// The name of the module holding the tests of the document named after
+/// `name_path`.
+fn module_name(name_path: &Path) -> String {
+    let name = doc_name(name_path);
+    match &name[..] {
+        "self" | "super" | "crate" => format!("{}_", name),
+        "abstract" | "as" | "async" | "await" | "become" | "box" | "break" | "const" |
+        "continue" | "do" | "dyn" | "else" | "enum" | "extern" | "false" | "final" | "fn" |
+        "for" | "gen" | "if" | "impl" | "in" | "let" | "loop" | "macro" | "match" | "mod" |
+        "move" | "mut" | "override" | "priv" | "pub" | "ref" | "return" | "static" |
+        "struct" | "trait" | "true" | "try" | "type" | "typeof" | "unsafe" | "unsized" |
+        "use" | "virtual" | "where" | "while" | "yield" => format!("r#{}", name),
+        _ => name,
+    }
 }
 
 /// Just like Rustdoc, ignore a "#" sign at the beginning of a line of code.
 /// These are commonly an indication to omit the line from user-facing
 /// documentation but include it for the purpose of playground links or skeptic
-/// testing.
-fn clean_omitted_line(line: &String) -> &str {
+/// testing. A line starting with `##` is shown, and escapes a literal `#`.
+/// Lines may end with `\n` or `\r\n`.
+fn clean_omitted_line(line: &str) -> &str {
     let trimmed = line.trim_left();
-    if trimmed == "#\n" {
+    if trimmed.trim_end() == "#" || trimmed.starts_with("##") {
         &trimmed[1..]
     } else if trimmed.starts_with("# ") {
         &trimmed[2..]
@@ -327,192 +2301,3839 @@ fn clean_omitted_line(line: &String) -> &str {
     }
 }
 
-/// Creates the Rust code that this test will be operating on.
-fn create_test_input(lines: &[String]) -> String {
-    lines.iter().map(clean_omitted_line).collect()
+/// Whether a line of an example is hidden from the documentation.
+fn is_hidden_line(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    trimmed.trim_end() == "#" || trimmed.starts_with("# ")
 }
 
-fn create_test_runner(config: &Config,
-                      template: &Option<String>,
-                      test: &Test)
-                      -> Result<String, IoError> {
-
-    let template = template.clone().unwrap_or_else(|| String::from("{}"));
-    let test_text = create_test_input(&test.text);
+/// Creates the Rust code that this test will be operating on. Cleaning
+/// lines only ever removes from them, so the code is built in a single
+/// allocation of the size of the text.
+fn create_test_input(text: &str) -> String {
+    let mut input = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        input.push_str(clean_omitted_line(line));
+    }
+    input
+}
 
-    let mut s: Vec<u8> = Vec::new();
-    if test.ignore {
-        try!(writeln!(s, "#[ignore]"));
+/// Splits the leading `#![...]` attributes off the code of an example, so
+/// they can be put at the top of the test, where they apply to the crate.
+fn split_crate_attributes(code: &str) -> (&str, &str) {
+    let mut attributes_len = 0;
+    let mut offset = 0;
+    let mut in_attribute = false;
+    for line in code.split_inclusive('\n') {
+        let trimmed = line.trim();
+        offset += line.len();
+        if in_attribute || trimmed.starts_with("#![") {
+            in_attribute = !trimmed.ends_with(']');
+        } else if trimmed.is_empty() || trimmed.starts_with("//") {
+            continue;
+        } else {
+            break;
+        }
+        if !in_attribute {
+            attributes_len = offset;
+        }
     }
-    if test.should_panic {
-        try!(writeln!(s, "#[should_panic]"));
+    code.split_at(attributes_len)
+}
+
+/// Like rustdoc, wraps the code of an example that doesn't declare a `main`
+/// in one. The leading `extern crate`, `use` and `mod <name>;` items, along
+/// with their attributes, are kept outside of `main`. When the example uses `?` outside
+/// of any block, `main` returns a `Result` so that it can.
+fn wrap_in_main(code: &str) -> String {
+    let declares_main = code.lines().any(|line| {
+        let line = line.trim_start();
+        let line = line.strip_prefix("pub ").unwrap_or(line);
+        line.starts_with("fn main(") || line.starts_with("fn main ")
+    });
+    if declares_main {
+        return code.to_string();
     }
 
-    try!(writeln!(s, "#[test] fn {}() {{", test.name));
-    try!(writeln!(s,
-                  "    let s = &format!(r####\"{}{}\"####, r####\"{}\"####);",
-                  "\n",
-                  template,
-                  test_text));
+    let lines = code.split_inclusive('\n').collect::<Vec<_>>();
+    // The number of lines kept outside of `main`. Blank lines, comments and
+    // attributes are only kept there if an `extern crate` or `use` follows.
+    let mut header_len = 0;
+    let mut in_item = false;
+    for (i, line) in lines.iter().enumerate() {
+        let trimmed = line.trim();
+        if in_item || trimmed.starts_with("extern crate ") || trimmed.starts_with("use ") ||
+           trimmed.starts_with("pub use ") {
+            in_item = !trimmed.ends_with(';');
+        } else if (trimmed.starts_with("mod ") || trimmed.starts_with("pub mod ")) &&
+                  trimmed.ends_with(';') {
+            // A module in a file of its own can't be declared in `main`
+        } else if trimmed.is_empty() || trimmed.starts_with("//") || trimmed.starts_with("#[") {
+            continue;
+        } else {
+            break;
+        }
+        if !in_item {
+            header_len = i + 1;
+        }
+    }
 
-    // if we are not running, just compile the test without running it
-    if test.no_run {
-        try!(writeln!(s,
-            "    skeptic::rt::compile_test(r#\"{}\"#, s);",
-            config.out_dir.to_str().unwrap()));
+    // `main` is opened on the first line of its body, so that the lines of
+    // the example keep their numbers.
+    let body = lines[header_len..].concat();
+    let returns_result = uses_question_mark(&body);
+    let mut out = lines[..header_len].concat();
+    if returns_result {
+        out.push_str("fn main() -> Result<(), Box<dyn std::error::Error>> {");
     } else {
-        try!(writeln!(s,
-            "    skeptic::rt::run_test(r#\"{}\"#, s);",
-            config.out_dir.to_str().unwrap()));
+        out.push_str("fn main() {");
     }
-
-    try!(writeln!(s, "}}"));
-    try!(writeln!(s, ""));
-
-    Ok(String::from_utf8(s).unwrap())
+    if body.is_empty() {
+        out.push('\n');
+    } else {
+        out.push(' ');
+        out.push_str(&body);
+    }
+    if !out.ends_with('\n') {
+        out.push('\n');
+    }
+    // The example may end with its own `Ok(())`, or `Ok::<(), E>(())`
+    let last_line = body.lines().rev().map(str::trim).find(|line| !line.is_empty()).unwrap_or("");
+    if returns_result && (!last_line.starts_with("Ok") || last_line.ends_with(';')) {
+        out.push_str("Ok(())\n");
+    }
+    out.push_str("}\n");
+    out
 }
 
-fn write_if_contents_changed(name: &Path, contents: &str) -> Result<(), IoError> {
-    // Can't open in write mode now as that would modify the last changed timestamp of the file
-    match File::open(name) {
-        Ok(mut file) => {
-            let mut current_contents = String::new();
-            try!(file.read_to_string(&mut current_contents));
-            if current_contents == contents {
-                // No change avoid writing to avoid updating the timestamp of the file
-                return Ok(())
+/// Whether the code uses the `?` operator outside of any block, ignoring
+/// comments, string and character literals, and `?Sized`-like bounds.
+fn uses_question_mark(code: &str) -> bool {
+    let chars = code.chars().collect::<Vec<_>>();
+    let mut depth = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '/' if chars.get(i + 1) == Some(&'/') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            'r' if chars.get(i + 1) == Some(&'"') || chars.get(i + 1) == Some(&'#') => {
+                let hashes = chars[i + 1..].iter().take_while(|&&c| c == '#').count();
+                if chars.get(i + 1 + hashes) == Some(&'"') {
+                    i += hashes + 2;
+                    while i < chars.len() &&
+                          !(chars[i] == '"' &&
+                            chars[i + 1..].iter().take_while(|&&c| c == '#').count() >= hashes) {
+                        i += 1;
+                    }
+                    i += hashes;
+                }
+            }
+            '"' => {
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    if chars[i] == '\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            }
+            // A character literal, rather than a lifetime
+            '\'' if chars.get(i + 1) == Some(&'\\') => {
+                i += 2;
+                while i < chars.len() && chars[i] != '\'' {
+                    i += 1;
+                }
+            }
+            '\'' if chars.get(i + 2) == Some(&'\'') => i += 2,
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '?' if depth == 0 && !chars.get(i + 1).map(|c| c.is_alphabetic()).unwrap_or(false) => {
+                return true;
             }
+            _ => {}
         }
-        Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
-        Err(err) => return Err(err),
+        i += 1;
     }
-    let mut file = try!(File::create(name));
-    try!(file.write(contents.as_bytes()));
-    Ok(())
+    false
 }
 
-pub mod rt {
-    use std::env;
-    use std::fs::{self, File};
-    use std::io::{self, Write};
-    use std::path::{Path, PathBuf};
-    use std::process::Command;
-    use std::ffi::OsStr;
-    use tempdir::TempDir;
-
-    pub fn compile_test(out_dir: &str, test_text: &str) {
-        let ref rustc = env::var("RUSTC").unwrap_or(String::from("rustc"));
-        let ref outdir = TempDir::new("rust-skeptic").unwrap();
-        let ref testcase_path = outdir.path().join("test.rs");
-        let ref binary_path = outdir.path().join("out.exe");
-
-        write_test_case(testcase_path, test_text);
-        compile_test_case(testcase_path, binary_path, rustc, out_dir);
-    }
-
-    pub fn run_test(out_dir: &str, test_text: &str) {
-        let ref rustc = env::var("RUSTC").unwrap_or(String::from("rustc"));
-        let ref outdir = TempDir::new("rust-skeptic").unwrap();
-        let ref testcase_path = outdir.path().join("test.rs");
-        let ref binary_path = outdir.path().join("out.exe");
-
-        write_test_case(testcase_path, test_text);
-        compile_test_case(testcase_path, binary_path, rustc, out_dir);
-        run_test_case(binary_path, outdir.path());
-    }
+/// The code of an example, as inserted in a template.
+struct Snippet<'a> {
+    /// The hidden lines at the start of the example.
+    before: &'a str,
+    /// The code between the hidden lines at the start and at the end.
+    code: &'a str,
+    /// The hidden lines at the end of the example.
+    after: &'a str,
+}
 
-    fn write_test_case(path: &Path, test_text: &str) {
-        let mut file = File::create(path).unwrap();
-        file.write_all(test_text.as_bytes()).unwrap();
+/// Checks that the template can be expanded, and that the code of the
+/// examples ends up in it.
+fn check_template(template_name: &str, template: &str) -> Result<(), SkepticError> {
+    let marker = "\u{0}skeptic example\u{0}";
+    let snippet = Snippet { before: "", code: marker, after: "" };
+    let vars = [("test_name", ""), ("crate_name", ""), ("crate_version", "")];
+    let expanded = expand_template(template_name, template, &snippet, &vars)?;
+    if !expanded.contains(marker) {
+        return Err(SkepticError::Invalid(
+            format!("template {}: no `{{}}` or `{{snippet}}` placeholder for the code of the \
+                     examples", template_name)));
     }
+    Ok(())
+}
 
-    fn compile_test_case(in_path: &Path, out_path: &Path, rustc: &str, out_dir: &str) {
-
-        // FIXME: Hack. Because the test runner uses rustc to build
-        // tests and those tests expect access to the crate this
-        // project builds and its deps, we need to find the directory
-        // containing Cargo's deps to pass as a `-L` flag to
-        // rustc. Cargo does not give us this directly, but we know
-        // relative to OUT_DIR where to look.
-        let mut target_dir = PathBuf::from(out_dir);
-        target_dir.pop();
-        target_dir.pop();
-        target_dir.pop();
-        let mut deps_dir = target_dir.clone();
-        deps_dir.push("deps");
+enum TemplatePart {
+    Text(String),
+    Placeholder(String),
+}
 
-        let mut cmd = Command::new(rustc);
-        cmd.arg(in_path)
-            .arg("--verbose")
-            .arg("-o").arg(out_path)
-            .arg("--crate-type=bin")
-            .arg("-L").arg(target_dir)
-            .arg("-L").arg(&deps_dir);
+/// Substitutes the placeholders of a template: `{before}` and `{after}` are
+/// the hidden lines at the start and at the end of the example, `{}` and
+/// `{snippet}` are the rest of its code, or all of it if the template has
+/// neither `{before}` nor `{after}`, the placeholders named in `vars`, like
+/// `{test_name}`, are their values, and `{{` and `}}` are literal braces.
+/// Any other placeholder is an error naming the template.
+fn expand_template(template_name: &str,
+                   template: &str,
+                   snippet: &Snippet,
+                   vars: &[(&str, &str)])
+                   -> Result<String, SkepticError> {
+    let error = |message: String| {
+        SkepticError::Invalid(format!("template {}: {}", template_name, message))
+    };
 
-        for dep in fs::read_dir(deps_dir).expect("failed to access target/*/deps") {
-            let dep = dep.expect("failed to read files from target/*/deps");
-            let dep = dep.path();
-            if let Some(name) = dep.file_stem().and_then(OsStr::to_str) {
-                if let Some(ext) = dep.extension() {
-                    if ext == "rlib" {
-                        if let Some(libname) = name.rsplitn(2, '-').nth(1) {
-                            let libname = &libname[3..];
-                            cmd.arg("--extern");
-                            cmd.arg(format!("{}={}", libname, dep.to_str().expect("filename not utf8")));
-                        }
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(error(String::from("unclosed `{`"))),
                     }
                 }
+                parts.push(TemplatePart::Text(mem::take(&mut text)));
+                parts.push(TemplatePart::Placeholder(placeholder.trim().to_string()));
             }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '}' => return Err(error(String::from("unmatched `}`, use `}}` for a literal brace"))),
+            c => text.push(c),
         }
-
-        interpret_output(cmd);
     }
+    parts.push(TemplatePart::Text(text));
 
-    fn run_test_case(program_path: &Path, outdir: &Path) {
-        let mut cmd = Command::new(program_path);
-        cmd.current_dir(outdir);
-        interpret_output(cmd);
+    let splits_snippet = parts.iter().any(|part| match *part {
+        TemplatePart::Placeholder(ref p) => p == "before" || p == "after",
+        TemplatePart::Text(_) => false,
+    });
+    let whole_snippet = format!("{}{}{}", snippet.before, snippet.code, snippet.after);
+
+    let mut out = String::with_capacity(template.len() + whole_snippet.len());
+    for part in &parts {
+        match *part {
+            TemplatePart::Text(ref text) => out.push_str(text),
+            TemplatePart::Placeholder(ref placeholder) => {
+                match &placeholder[..] {
+                    "" | "0" | "snippet" if splits_snippet => out.push_str(snippet.code),
+                    "" | "0" | "snippet" => out.push_str(&whole_snippet),
+                    "before" => out.push_str(snippet.before),
+                    "after" => out.push_str(snippet.after),
+                    other => match vars.iter().find(|&&(name, _)| name == other) {
+                        Some(&(_, value)) => out.push_str(value),
+                        None => return Err(error(format!("unknown placeholder `{{{}}}`", other))),
+                    },
+                }
+            }
+        }
     }
+    Ok(out)
+}
 
-    fn interpret_output(mut command: Command) {
-        let output = command.output().unwrap();
-        write!(io::stdout(),
-               "{}",
-               String::from_utf8(output.stdout).unwrap())
-            .unwrap();
-        write!(io::stderr(),
-               "{}",
-               String::from_utf8(output.stderr).unwrap())
-            .unwrap();
+/// Groups the examples of a document that `SKEPTIC_BATCH` compiles into a
+/// single binary, by the indices of their tests. Only examples that are run
+/// are batched, with those of the same edition, and not if they have crate
+/// attributes, which would apply to the whole binary, or need features,
+/// without which they wouldn't compile along with the others, or are
+/// `check`ed, which the others might not pass, or don't unwind, or have
+/// auxiliary files, whose modules would be those of the whole binary.
+fn batches(tests: &[(ExtractedTest, String)]) -> Vec<Vec<usize>> {
+    let mut batches: Vec<(&Option<String>, Vec<usize>)> = Vec::new();
+    for (i, (test, test_text)) in tests.iter().enumerate() {
+        if test.ignore || test.no_run || test.compile_fail || test.check_only ||
+           !test.required_features.is_empty() ||
+           test.check || test.panic_abort || !test.aux_files.is_empty() ||
+           !split_crate_attributes(test_text).0.is_empty() {
+            continue;
+        }
+        match batches.iter_mut().find(|batch| *batch.0 == test.edition) {
+            Some(batch) => batch.1.push(i),
+            None => batches.push((&test.edition, vec![i])),
+        }
+    }
+    // A single example gains nothing from being batched
+    batches.into_iter().map(|batch| batch.1).filter(|batch| batch.len() > 1).collect()
+}
+
+/// Creates the code rustc compiles for the example, from its template if it
+/// has one.
+fn create_test_text(config: &Config,
+                    template: Option<(&str, &str)>,
+                    test: &ExtractedTest)
+                    -> Result<String, SkepticError> {
+    let input = create_test_input(&test.text);
+    let (crate_attributes, code) = split_crate_attributes(&input);
+    let code = match template {
+        Some((template_name, template)) => {
+            // The hidden lines are found in the text of the example, after
+            // its crate attributes.
+            let lines = test.text.split_inclusive('\n').collect::<Vec<_>>();
+            let lines = &lines[crate_attributes.split_inclusive('\n').count()..];
+            let before_len = lines.iter().take_while(|line| is_hidden_line(line)).count();
+            let after_len = lines[before_len..].iter().rev().take_while(|line| is_hidden_line(line)).count();
+            let before = create_test_input(&lines[..before_len].concat());
+            let code = create_test_input(&lines[before_len..lines.len() - after_len].concat());
+            let after = create_test_input(&lines[lines.len() - after_len..].concat());
+            let snippet = Snippet { before: &before, code: &code, after: &after };
+            let mut vars = vec![("test_name", &test.name[..])];
+            if let Some(ref name) = config.crate_name {
+                vars.push(("crate_name", name));
+            }
+            if let Some(ref version) = config.crate_version {
+                vars.push(("crate_version", version));
+            }
+            expand_template(template_name, template, &snippet, &vars)?
+        }
+        None => wrap_in_main(code),
+    };
+    Ok(format!("{}{}", crate_attributes, code))
+}
+
+/// Creates the test function of the example, whose code is `test_text`.
+/// `batch` is the static holding the examples it is batched with, if any,
+/// and its index there.
+fn create_test_runner(config: &Config,
+                      test: &ExtractedTest,
+                      test_text: &str,
+                      location: &str,
+                      batch: Option<(&str, usize)>)
+                      -> String {
+    // Writing to a Vec can't fail
+    let mut s: Vec<u8> = Vec::new();
+    let features = test.required_features.iter()
+        .map(|feature| format!("feature = {:?}", feature))
+        .collect::<Vec<_>>();
+    match features.len() {
+        0 => {}
+        1 => writeln!(s, "#[cfg({})]", features[0]).unwrap(),
+        _ => writeln!(s, "#[cfg(all({}))]", features.join(", ")).unwrap(),
+    }
+    if test.ignore {
+        match test.ignore_reason {
+            Some(ref reason) => writeln!(s, "#[ignore = {:?}]", reason).unwrap(),
+            None => writeln!(s, "#[ignore]").unwrap(),
+        }
+    } else {
+        let mut cfgs = Vec::new();
+        if test.no_miri {
+            cfgs.push("miri");
+        }
+        if let Some(ref cfg) = config.ignore_cfg {
+            cfgs.push(cfg);
+        }
+        match cfgs.len() {
+            0 => {}
+            1 => writeln!(s, "#[cfg_attr({}, ignore)]", cfgs[0]).unwrap(),
+            _ => writeln!(s, "#[cfg_attr(any({}), ignore)]", cfgs.join(", ")).unwrap(),
+        }
+    }
+    if test.should_panic {
+        match test.expected_panic {
+            Some(ref expected) => writeln!(s, "#[should_panic(expected = {:?})]", expected).unwrap(),
+            None => writeln!(s, "#[should_panic]").unwrap(),
+        }
+    }
+
+    writeln!(s, "#[test] fn {}() {{", test.name).unwrap();
+    match batch {
+        Some((name, i)) => writeln!(s, "    let s = {}[{}].1;", name, i).unwrap(),
+        None => writeln!(s, "    let s = {};", raw_string_literal(test_text)).unwrap(),
+    }
+
+    // if we expect a compilation failure, check that rustc rejects the test,
+    // and if we are not running, just compile the test without running it
+    let function = if test.compile_fail {
+        "compile_test_fail"
+    } else if test.no_run || test.check_only {
+        "compile_test"
+    } else {
+        "run_test"
+    };
+
+    writeln!(s,
+        "    skeptic::rt::{}_with({}, s, &{});",
+        function,
+        raw_string_literal(config.out_dir.to_str().unwrap()),
+        create_test_options(config, test, location, batch)).unwrap();
+
+    writeln!(s, "}}").unwrap();
+    writeln!(s).unwrap();
+
+    String::from_utf8(s).unwrap()
+}
+
+/// A raw string literal of `s`, with enough `#`s that no `"` followed by
+/// `#`s in `s` can end it early.
+fn raw_string_literal(s: &str) -> String {
+    let mut hashes = 0;
+    for (i, _) in s.match_indices('"') {
+        let run = s[i + 1..].chars().take_while(|&c| c == '#').count();
+        hashes = hashes.max(run + 1);
+    }
+    let hashes = "#".repeat(hashes.max(1));
+    format!("r{}\"{}\"{}", hashes, s, hashes)
+}
+
+/// Creates the `skeptic::rt::TestOptions` expression for this test.
+fn create_test_options(config: &Config,
+                       test: &ExtractedTest,
+                       location: &str,
+                       batch: Option<(&str, usize)>)
+                       -> String {
+    let mut s = String::from("skeptic::rt::TestOptions {\n");
+    s.push_str(&format!("        name: Some(String::from({:?})),\n", test.name));
+    s.push_str(&format!("        crate_name: {},\n", option_literal(&config.crate_name)));
+    s.push_str(&format!("        location: Some(String::from({:?})),\n", location));
+    s.push_str(&format!("        edition: {},\n", option_literal(&test.edition)));
+    s.push_str(&format!("        target: {},\n", option_literal(&config.target)));
+    s.push_str(&format!("        expected_output: {},\n", option_literal(&test.expected_output)));
+    s.push_str(&format!("        expected_stderr: {},\n", option_literal(&test.expected_stderr)));
+    let externs = test.externs.iter()
+        .map(|e| format!("String::from({:?})", e))
+        .collect::<Vec<_>>();
+    s.push_str(&format!("        externs: vec![{}],\n", externs.join(", ")));
+    let error_codes = test.error_codes.iter()
+        .map(|e| format!("String::from({:?})", e))
+        .collect::<Vec<_>>();
+    s.push_str(&format!("        error_codes: vec![{}],\n", error_codes.join(", ")));
+    s.push_str(&format!("        should_panic: {},\n", test.should_panic));
+    s.push_str(&format!("        check: {},\n", test.check));
+    s.push_str(&format!("        panic_abort: {},\n", test.panic_abort));
+    let aux_files = test.aux_files.iter()
+        .map(|path| format!("::std::path::PathBuf::from({:?})", path))
+        .collect::<Vec<_>>();
+    s.push_str(&format!("        aux_files: vec![{}],\n", aux_files.join(", ")));
+    s.push_str(&format!("        check_only: {},\n", test.check_only));
+    match test.exit_code {
+        Some(code) => s.push_str(&format!("        exit_code: Some({}),\n", code)),
+        None => s.push_str("        exit_code: None,\n"),
+    }
+    let allows = if test.check { &[][..] } else { &config.default_allows[..] };
+    let allows = allows.iter()
+        .map(|lint| format!("String::from({:?})", lint))
+        .collect::<Vec<_>>();
+    s.push_str(&format!("        allows: vec![{}],\n", allows.join(", ")));
+    if test.cwd_manifest {
+        // The crate the tests are included in is built by cargo, which sets it
+        s.push_str("        current_dir: Some(::std::path::PathBuf::from(env!(\"CARGO_MANIFEST_DIR\"))),\n");
+    } else {
+        s.push_str("        current_dir: None,\n");
+    }
+    match batch {
+        Some((name, i)) => {
+            s.push_str(&format!("        batch: Some(skeptic::rt::Batch {{ examples: {}, index: {} }}),\n",
+                                name, i));
+        }
+        None => s.push_str("        batch: None,\n"),
+    }
+    s.push_str("    }");
+    s
+}
+
+fn option_literal(value: &Option<String>) -> String {
+    match *value {
+        Some(ref v) => format!("Some(String::from({:?}))", v),
+        None => String::from("None"),
+    }
+}
+
+fn write_if_contents_changed(name: &Path, contents: &str) -> Result<(), IoError> {
+    // Can't open in write mode now as that would modify the last changed timestamp of the file
+    match File::open(name) {
+        Ok(mut file) => {
+            let mut current_contents = String::new();
+            file.read_to_string(&mut current_contents)?;
+            if current_contents == contents {
+                // No change avoid writing to avoid updating the timestamp of the file
+                return Ok(())
+            }
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err),
+    }
+
+    // Write to a temporary file next to it first and rename that into place,
+    // so that no reader ever sees a partially written file.
+    let mut tmp_name = name.as_os_str().to_owned();
+    tmp_name.push(format!(".{}.tmp", process::id()));
+    let tmp_name = PathBuf::from(tmp_name);
+    let written = File::create(&tmp_name)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .and_then(|_| fs::rename(&tmp_name, name));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp_name);
+    }
+    written
+}
+
+pub mod rt {
+    use std::collections::btree_map::Entry;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::env;
+    use std::error::Error;
+    use std::fmt;
+    use std::fs::{self, File};
+    use std::hash::{Hash, Hasher};
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::path::{Path, PathBuf};
+    use std::process::{self, Command, Output, Stdio};
+    use std::ffi::{OsStr, OsString};
+    use std::os::raw::c_int;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    #[cfg(test)]
+    use std::time::SystemTime;
+    use tempdir::TempDir;
+    use super::env_flag;
+
+    // The generated tests already run on libtest's thread pool; these bound
+    // how many rustc processes those threads may run at once, and keep the
+    // output of each test from interleaving with another's.
+    static RUNNING_JOBS: Mutex<usize> = Mutex::new(0);
+    static JOB_FINISHED: Condvar = Condvar::new();
+    static OUTPUT: Mutex<()> = Mutex::new(());
+    /// The examples that compiled with warnings, as reported once the tests
+    /// have run.
+    static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    /// Per-test settings that affect how a test is compiled and run.
+    #[derive(Clone, Debug, Default)]
+    pub struct TestOptions {
+        /// The name of the test, and of the crate it tests, which its
+        /// temporary directory is named after.
+        pub name: Option<String>,
+        pub crate_name: Option<String>,
+        /// Where the example is in its document, as `<path>:<line>`.
+        pub location: Option<String>,
+        /// The edition passed to rustc with `--edition`, if any.
+        pub edition: Option<String>,
+        /// The target triple passed to rustc with `--target` when
+        /// cross-compiling. `SKEPTIC_TARGET` takes precedence over it.
+        pub target: Option<String>,
+        /// The stdout the example must print. Trailing whitespace is ignored.
+        pub expected_output: Option<String>,
+        /// Text the stderr of the example must contain. Surrounding
+        /// whitespace is ignored.
+        pub expected_stderr: Option<String>,
+        /// The crates the example needs, which must be among the dependencies
+        /// of the tested crate.
+        pub externs: Vec<String>,
+        /// The error codes rustc must report when the example is expected
+        /// not to compile.
+        pub error_codes: Vec<String>,
+        /// The directory the example is run in, instead of the temporary
+        /// directory it is compiled in.
+        pub current_dir: Option<PathBuf>,
+        /// Whether the test expects the example to panic, in which case it
+        /// is run even if `SKEPTIC_COMPILE_ONLY` is set.
+        pub should_panic: bool,
+        /// Whether the example is compiled with warnings, unused code
+        /// included, denied.
+        pub check: bool,
+        /// Whether the example is compiled with `-C panic=abort`.
+        pub panic_abort: bool,
+        /// Files copied into the directory of the example, which can
+        /// declare them as modules.
+        pub aux_files: Vec<PathBuf>,
+        /// The code the example must exit with, instead of exiting
+        /// successfully.
+        pub exit_code: Option<i32>,
+        /// Whether compiling the example stops at its metadata, with
+        /// `--emit=metadata`, which type-checks it without generating code
+        /// or linking, faster than compiling a binary that isn't run.
+        pub check_only: bool,
+        /// The lints allowed in the whole example, with a `#![allow(...)]`
+        /// crate attribute at its start.
+        pub allows: Vec<String>,
+        /// The examples the example is compiled along with when
+        /// `SKEPTIC_BATCH` is set.
+        pub batch: Option<Batch>,
+    }
+
+    /// Examples of a document that can be compiled into a single binary,
+    /// which runs the one named by its first argument.
+    #[derive(Clone, Copy, Debug)]
+    pub struct Batch {
+        /// The names of the tests of the examples, and their code.
+        pub examples: &'static [(&'static str, &'static str)],
+        /// The index of the example being tested in `examples`.
+        pub index: usize,
+    }
+
+    /// Why compiling or running an example failed.
+    #[derive(Debug)]
+    pub enum RtError {
+        /// Preparing the example, or starting a command, failed.
+        Io(io::Error),
+        /// rustc rejected the example.
+        CompileFailed {
+            /// The rustc command, as it would be typed in a shell.
+            command: String,
+            output: Output,
+        },
+        /// The example compiled, although it is expected not to.
+        CompileSucceeded {
+            /// The rustc command, as it would be typed in a shell.
+            command: String,
+        },
+        /// The example failed to compile, but without some of the error
+        /// codes it is expected to fail with.
+        MissingErrorCodes {
+            command: String,
+            missing: Vec<String>,
+            /// The error codes rustc did report.
+            actual: Vec<String>,
+        },
+        /// The example exited unsuccessfully.
+        RunFailed {
+            command: String,
+            output: Output,
+        },
+        /// A crate the example needs, from an `extern=` tag, is not among the
+        /// dependencies of the tested crate.
+        MissingExtern {
+            name: String,
+            deps_dir: PathBuf,
+        },
+        /// The example printed something other than its expected output.
+        OutputMismatch {
+            expected: String,
+            actual: String,
+        },
+        /// The stderr of the example doesn't contain the expected text.
+        StderrMismatch {
+            expected: String,
+            actual: String,
+        },
+        /// The example exited with another code than the expected one, or
+        /// was killed by a signal, when its code is `None`.
+        ExitCodeMismatch {
+            command: String,
+            expected: i32,
+            actual: Option<i32>,
+            output: Output,
+        },
+        /// A command was killed after running longer than
+        /// `SKEPTIC_TIMEOUT_SECS`.
+        TimedOut {
+            command: String,
+            timeout: Duration,
+        },
+    }
+
+    impl fmt::Display for RtError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                RtError::Io(ref error) => write!(f, "{}", error),
+                RtError::CompileFailed { ref command, .. } => {
+                    write!(f, "Command failed, to reproduce the failure run:\n{}", command)
+                }
+                RtError::CompileSucceeded { ref command } => {
+                    write!(f, "Command succeeded, but compilation was expected to fail:\n{}",
+                           command)
+                }
+                RtError::MissingErrorCodes { ref command, ref missing, ref actual } => {
+                    write!(f, "Compilation failed without error {}, ", missing.join(", "))?;
+                    if actual.is_empty() {
+                        write!(f, "and with no error codes:\n{}", command)
+                    } else {
+                        write!(f, "but with {}:\n{}", actual.join(", "), command)
+                    }
+                }
+                // Include the test's stderr so that `should_panic(expected = ...)`
+                // can match against the panic message of the test binary.
+                RtError::RunFailed { ref command, ref output } => {
+                    write!(f, "Command failed:\n{}\n{}",
+                           command,
+                           String::from_utf8_lossy(&output.stderr))
+                }
+                RtError::MissingExtern { ref name, ref deps_dir } => {
+                    write!(f, "The example needs crate `{}`, which is not in {}. \
+                               Is it a dependency or dev-dependency of the crate?",
+                           name, deps_dir.display())
+                }
+                RtError::OutputMismatch { ref expected, ref actual } => {
+                    write!(f, "The example's output is not the expected one \
+                               (-expected +actual):\n{}",
+                           diff_lines(expected, actual))
+                }
+                RtError::StderrMismatch { ref expected, ref actual } => {
+                    write!(f, "The example's stderr doesn't contain the expected text.\n\
+                               expected:\n{}\nactual:\n{}",
+                           expected, actual)
+                }
+                RtError::ExitCodeMismatch { ref command, expected, actual, ref output } => {
+                    match actual {
+                        Some(actual) => {
+                            write!(f, "The example exited with code {}, but was expected to exit \
+                                       with code {}:\n{}\n{}",
+                                   actual, expected, command,
+                                   String::from_utf8_lossy(&output.stderr))
+                        }
+                        None => {
+                            write!(f, "The example was killed by a signal, but was expected to \
+                                       exit with code {}:\n{}\n{}",
+                                   expected, command,
+                                   String::from_utf8_lossy(&output.stderr))
+                        }
+                    }
+                }
+                RtError::TimedOut { ref command, timeout } => {
+                    write!(f, "timed out after {} seconds:\n{}", timeout.as_secs(), command)
+                }
+            }
+        }
+    }
+
+    impl Error for RtError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match *self {
+                RtError::Io(ref error) => Some(error),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<io::Error> for RtError {
+        fn from(error: io::Error) -> RtError {
+            RtError::Io(error)
+        }
+    }
+
+    pub fn compile_test(out_dir: &str, test_text: &str) {
+        compile_test_with(out_dir, test_text, &TestOptions::default());
+    }
+
+    pub fn run_test(out_dir: &str, test_text: &str) {
+        run_test_with(out_dir, test_text, &TestOptions::default());
+    }
+
+    pub fn compile_test_fail(out_dir: &str, test_text: &str) {
+        compile_test_fail_with(out_dir, test_text, &TestOptions::default());
+    }
+
+    #[track_caller]
+    pub fn compile_test_with(out_dir: &str, test_text: &str, options: &TestOptions) {
+        if let Err(e) = try_compile_test(out_dir, test_text, options) {
+            panic!("{}", failure_message(options, &e));
+        }
+    }
+
+    #[track_caller]
+    pub fn run_test_with(out_dir: &str, test_text: &str, options: &TestOptions) {
+        if let Err(e) = try_run_test(out_dir, test_text, options) {
+            panic!("{}", failure_message(options, &e));
+        }
+    }
+
+    #[track_caller]
+    pub fn compile_test_fail_with(out_dir: &str, test_text: &str, options: &TestOptions) {
+        if let Err(e) = try_compile_test_fail(out_dir, test_text, options) {
+            panic!("{}", failure_message(options, &e));
+        }
+    }
+
+    /// The message a test panics with, which starts by saying which
+    /// example failed, as the error itself only says how.
+    fn failure_message(options: &TestOptions, error: &RtError) -> String {
+        let example = match options.name {
+            Some(ref name) => format!("example {}", name),
+            None => String::from("the example"),
+        };
+        match options.location {
+            Some(ref location) => format!("{}: {} failed: {}", location, example, error),
+            None if options.name.is_some() => format!("{} failed: {}", example, error),
+            None => error.to_string(),
+        }
+    }
+
+    /// Compiles the example, returning why it failed instead of panicking.
+    pub fn try_compile_test(out_dir: &str,
+                            test_text: &str,
+                            options: &TestOptions)
+                            -> Result<(), RtError> {
+        let ref rustc = env::var("RUSTC").unwrap_or(String::from("rustc"));
+        with_test_dir(options, |outdir| {
+            let testcase_path = &outdir.join("test.rs");
+            let binary_path = &outdir.join(if options.check_only { "out.rmeta" } else { "out.exe" });
+
+            write_test_case(testcase_path, test_text, options)?;
+            compile_test_case(testcase_path, binary_path, rustc, out_dir, options)
+        })
+    }
+
+    /// Compiles and runs the example, returning why it failed instead of
+    /// panicking.
+    pub fn try_run_test(out_dir: &str,
+                        test_text: &str,
+                        options: &TestOptions)
+                        -> Result<(), RtError> {
+        // The test of a `should_panic` example fails unless it's run
+        if env_flag("SKEPTIC_COMPILE_ONLY") && !options.should_panic {
+            return try_compile_test(out_dir, test_text, options);
+        }
+        let ref rustc = env::var("RUSTC").unwrap_or(String::from("rustc"));
+        if let Some(ref batch) = options.batch {
+            let runs = runner(options).is_some() ||
+                       target(options).map(|t| is_host(rustc, &t)).unwrap_or(true);
+            if env_flag("SKEPTIC_BATCH") && runs {
+                if let Some(binary) = compile_batch(batch, rustc, out_dir, options) {
+                    let name = batch.examples[batch.index].0;
+                    return with_test_dir(options, |outdir| run_test_case(&binary, &[name], outdir, options));
+                }
+            }
+        }
+        with_test_dir(options, |outdir| {
+            let testcase_path = &outdir.join("test.rs");
+            let binary_path = &outdir.join("out.exe");
+
+            write_test_case(testcase_path, test_text, options)?;
+            compile_test_case(testcase_path, binary_path, rustc, out_dir, options)?;
+            match target(options) {
+                Some(ref target) if !is_host(rustc, target) && runner(options).is_none() => {
+                    let _output = lock(&OUTPUT);
+                    let _ = writeln!(io::stderr(),
+                                     "skeptic: not running the example, which is compiled for {}",
+                                     target);
+                    Ok(())
+                }
+                _ => run_test_case(binary_path, &[], outdir, options),
+            }
+        })
+    }
+
+    /// Checks that the example fails to compile, returning an error instead
+    /// of panicking if it doesn't.
+    pub fn try_compile_test_fail(out_dir: &str,
+                                 test_text: &str,
+                                 options: &TestOptions)
+                                 -> Result<(), RtError> {
+        let rustc = &env::var("RUSTC").unwrap_or(String::from("rustc"));
+        with_test_dir(options, |outdir| {
+            let testcase_path = &outdir.join("test.rs");
+            let binary_path = &outdir.join("out.exe");
+
+            write_test_case(testcase_path, test_text, options)?;
+            let mut cmd = rustc_command(testcase_path, binary_path, rustc, out_dir, options)?;
+            if options.error_codes.is_empty() {
+                let _job = JobToken::acquire();
+                if run_command(&mut cmd)?.status.success() {
+                    return Err(RtError::CompileSucceeded { command: shell_command(&cmd) });
+                }
+                return Ok(());
+            }
+
+            cmd.arg("--error-format=json");
+            let output = {
+                let _job = JobToken::acquire();
+                collect_output(&mut cmd)?
+            };
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // Forward the diagnostics as rustc would print them without
+            // `--error-format=json`
+            let rendered = stderr.lines()
+                .filter_map(|line| json_string_field(line, "rendered"))
+                .collect::<String>();
+            forward_output(&output.stdout, rendered.as_bytes());
+            if output.status.success() {
+                return Err(RtError::CompileSucceeded { command: shell_command(&cmd) });
+            }
+
+            let mut actual = stderr.lines()
+                .filter_map(|line| {
+                    let code = &line[line.find("\"code\":{")? + "\"code\":".len()..];
+                    json_string_field(code, "code")
+                })
+                .collect::<Vec<_>>();
+            actual.sort();
+            actual.dedup();
+            let missing = options.error_codes.iter()
+                .filter(|code| !actual.contains(code))
+                .cloned()
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                return Err(RtError::MissingErrorCodes {
+                    command: shell_command(&cmd),
+                    missing,
+                    actual,
+                });
+            }
+            Ok(())
+        })
+    }
+
+    /// The value of the first string field named `name` in a line of JSON,
+    /// unescaped. Nested objects are searched too.
+    fn json_string_field(json: &str, name: &str) -> Option<String> {
+        let start = json.find(&format!("\"{}\":\"", name))? + name.len() + 4;
+        let mut value = String::new();
+        let mut chars = json[start..].chars();
+        loop {
+            match chars.next()? {
+                '"' => return Some(value),
+                '\\' => {
+                    match chars.next()? {
+                        'n' => value.push('\n'),
+                        't' => value.push('\t'),
+                        'r' => value.push('\r'),
+                        'b' => value.push('\u{8}'),
+                        'f' => value.push('\u{c}'),
+                        'u' => {
+                            let unit = |chars: &mut ::std::str::Chars| {
+                                let hex = chars.take(4).collect::<String>();
+                                u32::from_str_radix(&hex, 16).ok()
+                            };
+                            let mut c = unit(&mut chars)?;
+                            // Characters outside the BMP are escaped as a
+                            // surrogate pair
+                            if (0xd800..0xdc00).contains(&c) {
+                                if chars.next()? != '\\' || chars.next()? != 'u' {
+                                    return None;
+                                }
+                                let low = unit(&mut chars)?;
+                                c = 0x10000 + ((c - 0xd800) << 10) + low.checked_sub(0xdc00)?;
+                            }
+                            value.push(::std::char::from_u32(c).unwrap_or('\u{fffd}'));
+                        }
+                        c => value.push(c),
+                    }
+                }
+                c => value.push(c),
+            }
+        }
+    }
+
+    /// Compiles the examples of the batch into one binary, once per test
+    /// process, and returns its path. `None` if they don't compile together,
+    /// in which case each one is compiled on its own.
+    fn compile_batch(batch: &Batch,
+                     rustc: &str,
+                     out_dir: &str,
+                     options: &TestOptions)
+                     -> Option<PathBuf> {
+        type Slot = Arc<Mutex<Option<Option<PathBuf>>>>;
+        static BATCHES: Mutex<BTreeMap<(usize, usize), Slot>> = Mutex::new(BTreeMap::new());
+
+        // Each batch is a distinct static, so its address identifies it.
+        // The map is only locked to find the batch's slot, so that batches
+        // of different documents compile concurrently.
+        let key = (batch.examples.as_ptr() as usize, batch.examples.len());
+        let slot = lock(&BATCHES).entry(key).or_default().clone();
+        let mut binary = lock(&slot);
+        binary.get_or_insert_with(|| {
+            let binary = build_batch(batch, rustc, out_dir, options);
+            if binary.is_none() {
+                let _output = lock(&OUTPUT);
+                let _ = writeln!(io::stderr(),
+                                 "skeptic: {} failed to compile along with the other \
+                                  examples of its document, compiling them separately",
+                                 batch.examples[batch.index].0);
+            }
+            binary
+        }).clone()
+    }
+
+    fn build_batch(batch: &Batch,
+                   rustc: &str,
+                   out_dir: &str,
+                   options: &TestOptions)
+                   -> Option<PathBuf> {
+        let source = batch_source(batch.examples, &options.allows);
+        let dir = TempDir::new(&format!("{}-batch", temp_dir_prefix(options))).ok()?;
+        let in_path = &dir.path().join("batch.rs");
+        let out_path = &dir.path().join("batch.exe");
+        fs::write(in_path, &source).ok()?;
+
+        // The crates needed by the example being tested aren't necessarily
+        // needed by the others, so they are checked when it falls back.
+        let options = TestOptions { externs: Vec::new(), ..options.clone() };
+        let mut cmd = rustc_command(in_path, out_path, rustc, out_dir, &options).ok()?;
+        // With warnings denied, the examples that have some are compiled on
+        // their own, which reports them
+        if env_flag("SKEPTIC_DENY_WARNINGS") {
+            cmd.arg("-D").arg("warnings");
+        }
+        let output = {
+            let _job = JobToken::acquire();
+            collect_output(&mut cmd).ok()?
+        };
         if !output.status.success() {
-            panic!("Command failed:\n{:?}", command);
+            return None;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        let binary = Path::new(out_dir)
+            .join("skeptic-batch")
+            .join(format!("{:016x}", hasher.finish()));
+        store_cached_binary(out_path, &binary);
+        if binary.exists() { Some(binary) } else { None }
+    }
+
+    /// The source of a batch, where each example is in a module named after
+    /// its test, and `main` calls the `main` of the one named by the first
+    /// argument. The exit code of an example whose `main` returns a `Result`
+    /// is found the way `std::process::Termination` would, which older
+    /// compilers don't have.
+    fn batch_source(examples: &[(&str, &str)], allows: &[String]) -> String {
+        let mut source = String::new();
+        for &(name, text) in examples {
+            source.push_str(&format!("mod {} {{\n", name));
+            if !allows.is_empty() {
+                source.push_str(&format!("#![allow({})]\n", allows.join(", ")));
+            }
+            source.push_str(&format!("{}\n", text));
+            source.push_str("pub fn skeptic_main() -> i32 {\n");
+            source.push_str("    super::SkepticTermination::skeptic_report(main())\n");
+            source.push_str("}\n}\n\n");
         }
+        source.push_str(BATCH_TERMINATION);
+        source.push_str("fn main() {\n");
+        source.push_str("    let code = match ::std::env::args().nth(1).as_ref().map(|s| &s[..]) {\n");
+        for &(name, _) in examples {
+            source.push_str(&format!("        Some({:?}) => {}::skeptic_main(),\n", name, name));
+        }
+        source.push_str("        other => panic!(\"no example named {:?}\", other),\n");
+        source.push_str("    };\n");
+        source.push_str("    if code != 0 {\n");
+        source.push_str("        ::std::process::exit(code);\n");
+        source.push_str("    }\n}\n");
+        source
     }
+
+    /// The exit codes of the return types `main` can have in a batch.
+    const BATCH_TERMINATION: &str = "\
+trait SkepticTermination {
+    fn skeptic_report(self) -> i32;
 }
 
-#[test]
-fn test_omitted_lines() {
-    let lines = &[
-        "# use std::collections::BTreeMap as Map;\n".to_owned(),
-        "#\n".to_owned(),
-        "#[allow(dead_code)]\n".to_owned(),
-        "fn main() {\n".to_owned(),
-        "    let map = Map::new();\n".to_owned(),
-        "    #\n".to_owned(),
-        "    # let _ = map;\n".to_owned(),
-        "}\n".to_owned(),
-    ];
+impl SkepticTermination for () {
+    fn skeptic_report(self) -> i32 {
+        0
+    }
+}
 
-    let expected = [
-        "use std::collections::BTreeMap as Map;\n",
-        "\n",
-        "#[allow(dead_code)]\n",
-        "fn main() {\n",
-        "    let map = Map::new();\n",
-        "\n",
-        "let _ = map;\n",
-        "}\n",
-    ].concat();
+impl<E: ::std::fmt::Debug> SkepticTermination for Result<(), E> {
+    fn skeptic_report(self) -> i32 {
+        match self {
+            Ok(()) => 0,
+            Err(err) => {
+                eprintln!(\"Error: {:?}\", err);
+                1
+            }
+        }
+    }
+}
+
+";
+
+    /// The target triple examples are compiled for, if it's given explicitly.
+    fn target(options: &TestOptions) -> Option<String> {
+        env::var("SKEPTIC_TARGET").ok().or_else(|| options.target.clone())
+    }
+
+    /// The program, and its arguments, that the binaries of examples are run
+    /// with instead of being run directly, from `SKEPTIC_RUNNER`, or from
+    /// cargo's `CARGO_TARGET_<triple>_RUNNER` for the target examples are
+    /// compiled for.
+    fn runner(options: &TestOptions) -> Option<Vec<String>> {
+        let runner = env::var("SKEPTIC_RUNNER").ok()
+            .or_else(|| env::var(runner_var(&target(options)?)).ok())?;
+        Some(split_flags(&runner)).filter(|words| !words.is_empty())
+    }
+
+    /// The name of cargo's environment variable for the runner of `target`.
+    fn runner_var(target: &str) -> String {
+        let target = target.to_uppercase().replace(['-', '.'], "_");
+        format!("CARGO_TARGET_{}_RUNNER", target)
+    }
+
+    /// Whether binaries built for `target` run on the host, which is assumed
+    /// when the host triple can't be found.
+    fn is_host(rustc: &str, target: &str) -> bool {
+        host_triple(rustc).map(|host| host == target).unwrap_or(true)
+    }
+
+    /// The host triple `rustc -vV` prints, looked up once.
+    fn host_triple(rustc: &str) -> Option<String> {
+        static HOST: Mutex<Option<Option<String>>> = Mutex::new(None);
+        let mut host = lock(&HOST);
+        host.get_or_insert_with(|| {
+            let output = Command::new(rustc).arg("-vV").output().ok()?;
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .find(|line| line.starts_with("host: "))
+                .map(|line| line["host: ".len()..].trim().to_string())
+        }).clone()
+    }
+
+    /// The temporary directory a test is compiled and run in. It is removed
+    /// when the test finishes, unless the test failed and `SKEPTIC_KEEP_TEMP`
+    /// is set, in which case its path is printed so it can be inspected.
+    struct TestDir {
+        dir: Option<TempDir>,
+        keep_on_failure: bool,
+        failed: bool,
+    }
+
+    impl TestDir {
+        fn new(options: &TestOptions) -> io::Result<TestDir> {
+            let mut prefix = temp_dir_prefix(options);
+            if let Some(ref name) = options.name {
+                prefix.push('-');
+                prefix.push_str(name);
+            }
+            Ok(TestDir {
+                dir: Some(TempDir::new(&prefix)?),
+                keep_on_failure: env_flag("SKEPTIC_KEEP_TEMP"),
+                failed: false,
+            })
+        }
+
+        fn path(&self) -> &Path {
+            self.dir.as_ref().unwrap().path()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            if !(self.keep_on_failure && (self.failed || thread::panicking())) {
+                return;
+            }
+            if let Some(dir) = self.dir.take() {
+                let path = dir.into_path();
+                let _output = lock(&OUTPUT);
+                let _ = writeln!(io::stderr(),
+                                 "skeptic: keeping the test directory {}",
+                                 path.display());
+            }
+        }
+    }
+
+    /// The start of the names of the temporary directories of the tests of
+    /// a crate, so that kept ones can be told apart.
+    fn temp_dir_prefix(options: &TestOptions) -> String {
+        match options.crate_name {
+            Some(ref crate_name) => format!("rust-skeptic-{}", crate_name),
+            None => String::from("rust-skeptic"),
+        }
+    }
+
+    /// Runs `f` in a new `TestDir`, which is marked as failed if `f` fails.
+    fn with_test_dir<F>(options: &TestOptions, f: F) -> Result<(), RtError>
+        where F: FnOnce(&Path) -> Result<(), RtError>
+    {
+        let mut dir = TestDir::new(options)?;
+        let result = f(dir.path());
+        dir.failed = result.is_err();
+        result
+    }
+
+    fn write_test_case(path: &Path, test_text: &str, options: &TestOptions) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        // rustc has no line directives, so at least say where the code
+        // its errors point at comes from, without shifting its lines.
+        if let Some(ref location) = options.location {
+            write!(file, "/* {} */ ", location)?;
+        }
+        if !options.allows.is_empty() {
+            write!(file, "#![allow({})] ", options.allows.join(", "))?;
+        }
+        file.write_all(test_text.as_bytes())?;
+        for aux in &options.aux_files {
+            let name = aux.file_name().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput,
+                               format!("{} is not a file", aux.display()))
+            })?;
+            fs::copy(aux, path.with_file_name(name)).map_err(|e| {
+                io::Error::new(e.kind(), format!("failed to copy {}: {}", aux.display(), e))
+            })?;
+        }
+        Ok(())
+    }
+
+    fn compile_test_case(in_path: &Path,
+                         out_path: &Path,
+                         rustc: &str,
+                         out_dir: &str,
+                         options: &TestOptions)
+                         -> Result<(), RtError> {
+        let mut cmd = rustc_command(in_path, out_path, rustc, out_dir, options)?;
+        // Hollow examples, like one whose code is all hidden by a stray `#`,
+        // compile with warnings about unused code
+        if options.check {
+            cmd.arg("-D").arg("warnings").arg("-D").arg("unused");
+        }
+        if options.check_only {
+            cmd.arg("--emit=metadata");
+        }
+        if env_flag("SKEPTIC_DENY_WARNINGS") {
+            cmd.arg("-D").arg("warnings");
+        }
+        // Every example is a crate named `test`, so each test has its own
+        // incremental directory, rather than evicting the others' sessions
+        if env_flag("SKEPTIC_INCREMENTAL") {
+            let name = options.name.as_ref().map_or("example", |name| &name[..]);
+            let dir = Path::new(out_dir).join("skeptic-incremental").join(name);
+            cmd.arg("-C").arg(format!("incremental={}", dir.display()));
+        }
+        let cached = cached_binary_path(&cmd, in_path, out_path, out_dir, options);
+        if let Some(ref cached) = cached {
+            if fs::copy(cached, out_path).is_ok() {
+                // Otherwise an example with warnings looks clean once cached
+                report_warnings(options, cached_warnings(cached));
+                return Ok(());
+            }
+        }
 
-    assert_eq!(create_test_input(lines), expected);
+        let warnings;
+        {
+            let _job = JobToken::acquire();
+            let output = run_command(&mut cmd)?;
+            if !output.status.success() {
+                return Err(RtError::CompileFailed {
+                    command: shell_command(&cmd),
+                    output,
+                });
+            }
+            warnings = count_warnings(&String::from_utf8_lossy(&output.stderr));
+            report_warnings(options, warnings);
+        }
+
+        if let Some(ref cached) = cached {
+            let _ = fs::write(cached.with_extension("warnings"), warnings.to_string());
+            store_cached_binary(out_path, cached);
+        }
+        Ok(())
+    }
+
+    /// The number of warnings the example cached at `cached` compiled with,
+    /// stored next to it.
+    fn cached_warnings(cached: &Path) -> usize {
+        fs::read_to_string(cached.with_extension("warnings")).ok()
+            .and_then(|warnings| warnings.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Adds the example to those listed once the tests have run, if it
+    /// compiled with warnings. The warnings themselves scroll by along with
+    /// the output of the other tests, so the list says which examples they
+    /// are from.
+    fn report_warnings(options: &TestOptions, warnings: usize) {
+        if warnings == 0 {
+            return;
+        }
+        let mut reports = lock(&WARNINGS);
+        if reports.is_empty() {
+            extern "C" {
+                fn atexit(callback: extern "C" fn()) -> c_int;
+            }
+            // The test harness has no hook for when the last test is done,
+            // but it exits then, both when the tests pass and when they fail
+            unsafe {
+                atexit(print_warnings);
+            }
+        }
+        reports.push(format!("{} compiled with {} warning{}",
+                             example_name(options),
+                             warnings,
+                             if warnings == 1 { "" } else { "s" }));
+    }
+
+    /// Prints the examples that compiled with warnings, once the tests have
+    /// run.
+    extern "C" fn print_warnings() {
+        let mut reports = lock(&WARNINGS);
+        reports.sort();
+        let mut stderr = io::stderr();
+        let _ = writeln!(stderr);
+        for report in reports.iter() {
+            let _ = writeln!(stderr, "skeptic: warning: {}", report);
+        }
+    }
+
+    /// Finds the directory of the profile the tested crate is built with,
+    /// e.g. `target/debug`, which contains the `deps` directory.
+    fn find_target_dir(out_dir: &Path,
+                       cargo_target_dir: Option<PathBuf>,
+                       target: Option<String>)
+                       -> PathBuf {
+        // FIXME: Hack. Because the test runner uses rustc to build
+        // tests and those tests expect access to the crate this
+        // project builds and its deps, we need to find the directory
+        // containing Cargo's deps to pass as a `-L` flag to
+        // rustc. Cargo does not give us this directly, but we know
+        // relative to OUT_DIR where to look.
+        let mut target_dir = out_dir.to_owned();
+        target_dir.pop();
+        target_dir.pop();
+        target_dir.pop();
+
+        // OUT_DIR is `<target-dir>/[<triple>/]<profile>/build/<crate>-<hash>/out`,
+        // so an explicit target dir and triple only leave the profile to find.
+        if let Some(cargo_target_dir) = cargo_target_dir {
+            if let Some(profile) = target_dir.file_name() {
+                let mut dir = cargo_target_dir;
+                if let Some(target) = target {
+                    dir.push(target);
+                }
+                dir.push(profile);
+                if dir.join("deps").is_dir() {
+                    return dir;
+                }
+            }
+        }
+
+        target_dir
+    }
+
+    /// Where the binary built by `cmd` is cached, keyed by a hash of the test
+    /// and of the rustc invocation. `None` if `SKEPTIC_NO_CACHE` is set.
+    fn cached_binary_path(cmd: &Command,
+                          in_path: &Path,
+                          out_path: &Path,
+                          out_dir: &str,
+                          options: &TestOptions)
+                          -> Option<PathBuf> {
+        if env_flag("SKEPTIC_NO_CACHE") {
+            return None;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        fs::read(in_path).ok()?.hash(&mut hasher);
+        for aux in &options.aux_files {
+            fs::read(aux).ok()?.hash(&mut hasher);
+        }
+        cmd.get_program().hash(&mut hasher);
+        // A toolchain update keeps the program name but not its output
+        rustc_version(cmd.get_program())?.hash(&mut hasher);
+        for arg in cmd.get_args() {
+            // The temporary paths are different for every run
+            if arg == in_path.as_os_str() || arg == out_path.as_os_str() {
+                continue;
+            }
+            arg.hash(&mut hasher);
+
+            // Rebuilding a dependency changes its contents but not its name
+            if let Some(path) = arg.to_str().and_then(|arg| arg.split_once('=')).map(|(_, path)| path) {
+                if let Ok(metadata) = fs::metadata(path) {
+                    metadata.len().hash(&mut hasher);
+                    if let Ok(modified) = metadata.modified() {
+                        modified.hash(&mut hasher);
+                    }
+                }
+            }
+        }
+
+        let name = format!("{:016x}", hasher.finish());
+        Some(Path::new(out_dir).join("skeptic-cache").join(name))
+    }
+
+    /// What `rustc -vV` prints, looked up once for each program.
+    fn rustc_version(rustc: &OsStr) -> Option<Vec<u8>> {
+        static VERSIONS: Mutex<BTreeMap<OsString, Option<Vec<u8>>>> = Mutex::new(BTreeMap::new());
+        let mut versions = lock(&VERSIONS);
+        versions.entry(rustc.to_owned()).or_insert_with(|| {
+            let output = Command::new(rustc).arg("-vV").output().ok()?;
+            if output.status.success() { Some(output.stdout) } else { None }
+        }).clone()
+    }
+
+    fn store_cached_binary(binary_path: &Path, cached: &Path) {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        // Copy under a unique name first, so that a concurrent test with the
+        // same source never sees a partially written binary.
+        let tmp = cached.with_extension(format!("{}-{}.tmp",
+                                                process::id(),
+                                                COUNTER.fetch_add(1, Ordering::SeqCst)));
+        let stored = cached.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::copy(binary_path, &tmp))
+            .and_then(|_| fs::rename(&tmp, cached));
+        if stored.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+
+    /// The number of rustc processes that may run at once, from `SKEPTIC_JOBS`
+    /// or else the available parallelism.
+    fn max_jobs() -> usize {
+        env::var("SKEPTIC_JOBS").ok()
+            .and_then(|jobs| jobs.parse().ok())
+            .filter(|&jobs| jobs > 0)
+            .unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1))
+    }
+
+    /// A slot for running one rustc process, released on drop. This is a
+    /// limit shared by the test threads, not a pool of workers: a test waits
+    /// for a slot on its own thread, which stays blocked meanwhile.
+    struct JobToken;
+
+    impl JobToken {
+        fn acquire() -> JobToken {
+            let max = max_jobs();
+            let mut running = lock(&RUNNING_JOBS);
+            while *running >= max {
+                running = JOB_FINISHED.wait(running).unwrap_or_else(|e| e.into_inner());
+            }
+            *running += 1;
+            JobToken
+        }
+    }
+
+    impl Drop for JobToken {
+        fn drop(&mut self) {
+            *lock(&RUNNING_JOBS) -= 1;
+            JOB_FINISHED.notify_one();
+        }
+    }
+
+    /// Locks a mutex, ignoring poisoning since the data it guards stays valid
+    /// even if a test panicked.
+    fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+        mutex.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn rustc_command(in_path: &Path,
+                     out_path: &Path,
+                     rustc: &str,
+                     out_dir: &str,
+                     options: &TestOptions)
+                     -> Result<Command, RtError> {
+        let target = target(options);
+        let target_dir = find_target_dir(Path::new(out_dir),
+                                         env::var_os("CARGO_TARGET_DIR").map(PathBuf::from),
+                                         target.clone());
+        let mut deps_dir = target_dir.clone();
+        deps_dir.push("deps");
+
+        let mut cmd = Command::new(rustc);
+        cmd.arg(in_path)
+            .arg("--verbose")
+            .arg("-o").arg(out_path)
+            .arg("--crate-type=bin")
+            .arg("-L").arg(target_dir)
+            .arg("-L").arg(&deps_dir);
+
+        if let Some(ref edition) = options.edition {
+            cmd.arg("--edition").arg(edition);
+        }
+        if let Some(ref target) = target {
+            cmd.arg("--target").arg(target);
+        }
+        if options.panic_abort {
+            cmd.arg("-C").arg("panic=abort");
+        }
+
+        let mut code = None;
+        let deps = cached_deps(&deps_dir, |name| {
+            let code = code.get_or_insert_with(|| fs::read_to_string(in_path).unwrap_or_default());
+            options.externs.iter().any(|e| e.replace('-', "_") == name) || uses_crate(code, name)
+        })?;
+        for name in &options.externs {
+            let name = name.replace('-', "_");
+            if !deps.libs.iter().any(|(libname, _)| *libname == name) {
+                return Err(RtError::MissingExtern {
+                    name,
+                    deps_dir,
+                });
+            }
+        }
+        for (libname, dep) in deps.libs.iter() {
+            cmd.arg("--extern");
+            cmd.arg(format!("{}={}", libname, dep.to_str().expect("filename not utf8")));
+        }
+
+        // Like cargo, which splits `RUSTFLAGS` on whitespace
+        if !env_flag("SKEPTIC_NO_RUSTFLAGS") {
+            if let Ok(flags) = env::var("RUSTFLAGS") {
+                cmd.args(flags.split_whitespace());
+            }
+        }
+        if let Some(sanitizer) = sanitizer() {
+            let host = if target.is_none() { host_triple(rustc) } else { None };
+            add_sanitizer(&mut cmd, &sanitizer, host);
+        }
+        add_sysroot(&mut cmd, env::var_os("SKEPTIC_SYSROOT"));
+        if let Ok(flags) = env::var("SKEPTIC_RUSTFLAGS") {
+            cmd.args(split_flags(&flags));
+        }
+
+        Ok(cmd)
+    }
+
+    /// The sanitizer examples are compiled with, from `SKEPTIC_SANITIZER`,
+    /// like `address`.
+    fn sanitizer() -> Option<String> {
+        env::var("SKEPTIC_SANITIZER").ok().filter(|s| !s.is_empty())
+    }
+
+    /// Passes rustc `-Zsanitizer`, and `--target` with the host triple when
+    /// there isn't a target already: sanitizers need an explicit target,
+    /// e.g. for the standard library built by `-Zbuild-std` to be used.
+    fn add_sanitizer(cmd: &mut Command, sanitizer: &str, host: Option<String>) {
+        if let Some(host) = host {
+            cmd.arg("--target").arg(host);
+        }
+        cmd.arg(format!("-Zsanitizer={}", sanitizer));
+    }
+
+    /// Passes rustc the sysroot it finds the standard library in, the value
+    /// of `SKEPTIC_SYSROOT`, for when `RUSTC` is a wrapper or the toolchain
+    /// isn't the one on `PATH`. An empty value is the same as none.
+    fn add_sysroot(cmd: &mut Command, sysroot: Option<OsString>) {
+        if let Some(sysroot) = sysroot.filter(|s| !s.is_empty()) {
+            cmd.arg("--sysroot").arg(sysroot);
+        }
+    }
+
+    /// Splits flags the way a POSIX shell splits words, honoring quotes and
+    /// backslash escapes.
+    fn split_flags(flags: &str) -> Vec<String> {
+        let mut words = Vec::new();
+        let mut word = None::<String>;
+        let mut quote = None;
+        let mut chars = flags.chars();
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (Some(q), c) if c == q => quote = None,
+                (Some('"'), '\\') | (None, '\\') => {
+                    if let Some(c) = chars.next() {
+                        word.get_or_insert_with(String::new).push(c);
+                    }
+                }
+                (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+                (None, '\'') | (None, '"') => {
+                    quote = Some(c);
+                    word.get_or_insert_with(String::new);
+                }
+                (None, c) if c.is_whitespace() => words.extend(word.take()),
+                (None, c) => word.get_or_insert_with(String::new).push(c),
+            }
+        }
+        words.extend(word);
+        words
+    }
+
+    /// The crates in the deps directory, as found by `find_deps` the first
+    /// time they are needed in the test process. Cargo has built them all
+    /// before running the tests, so they don't change while the tests run.
+    /// The ambiguous crates that `uses` says the example uses are warned
+    /// about, each once per process.
+    fn cached_deps<F>(deps_dir: &Path, uses: F) -> io::Result<Arc<Deps>>
+        where F: FnMut(&str) -> bool
+    {
+        static DEPS: Mutex<BTreeMap<PathBuf, Arc<Deps>>> = Mutex::new(BTreeMap::new());
+        let cached = lock(&DEPS).get(deps_dir).cloned();
+        let deps = match cached {
+            Some(deps) => deps,
+            None => {
+                // Scanned without holding the lock, so another test may scan
+                // the same directory concurrently, and keep the first result
+                let deps = Arc::new(find_deps(deps_dir)?);
+                lock(&DEPS).entry(deps_dir.to_owned()).or_insert(deps).clone()
+            }
+        };
+        if !deps.ambiguous.is_empty() {
+            warn_ambiguous_deps(deps_dir, &deps, uses);
+        }
+        Ok(deps)
+    }
+
+    /// The crates in a deps directory.
+    #[derive(Debug, PartialEq)]
+    struct Deps {
+        /// The file of each crate, by name.
+        libs: Vec<(String, PathBuf)>,
+        /// The crates there are several files of.
+        ambiguous: BTreeSet<String>,
+    }
+
+    /// The crates in the deps directory, by name. When a crate is there more
+    /// than once, e.g. after its version changed, the newest file is used.
+    /// That may not be the one the crate was built with, which is why the
+    /// examples using such a crate warn about it. Copies built from the same
+    /// sources, like those of a crate that is both a build dependency and a
+    /// dependency, are the same crate, so they aren't ambiguous.
+    fn find_deps(deps_dir: &Path) -> io::Result<Deps> {
+        let deps_error = |e: io::Error| {
+            io::Error::new(e.kind(), format!("failed to read {}: {}", deps_dir.display(), e))
+        };
+        let mut rlibs = BTreeMap::new();
+        let mut dylibs = BTreeMap::new();
+        let mut sources = BTreeMap::new();
+        for dep in fs::read_dir(deps_dir).map_err(deps_error)? {
+            let dep = dep.map_err(deps_error)?.path();
+            if let Some((libname, is_rlib)) = dep_crate_name(&dep) {
+                let source = dep_sources(&dep).unwrap_or_else(|| dep.display().to_string());
+                sources.entry((libname.clone(), is_rlib)).or_insert_with(BTreeSet::new).insert(source);
+                let libs = if is_rlib { &mut rlibs } else { &mut dylibs };
+                let modified = fs::metadata(&dep).and_then(|m| m.modified()).ok();
+                match libs.entry(libname) {
+                    Entry::Vacant(entry) => {
+                        entry.insert((modified, dep));
+                    }
+                    Entry::Occupied(mut entry) => {
+                        if (modified, &dep) > (entry.get().0, &entry.get().1) {
+                            entry.insert((modified, dep));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Proc macros and dylibs are only linked when there is no rlib of
+        // the same crate.
+        dylibs.retain(|name, _| !rlibs.contains_key(name));
+        let ambiguous = sources.into_iter()
+            .filter(|&((ref name, is_rlib), ref sources)| {
+                sources.len() > 1 && (is_rlib || dylibs.contains_key(name))
+            })
+            .map(|((name, _), _)| name)
+            .collect();
+        let libs = rlibs.into_iter()
+            .chain(dylibs)
+            .map(|(name, (_, dep))| (name, dep))
+            .collect::<Vec<_>>();
+        Ok(Deps {
+            libs,
+            ambiguous,
+        })
+    }
+
+    /// The sources of the crate a file in the deps directory is a library
+    /// of, from the first rule of the dep-info file cargo has rustc write
+    /// next to it, e.g. `foo-0123abcd.d` for `libfoo-0123abcd.rlib`.
+    fn dep_sources(dep: &Path) -> Option<String> {
+        let stem = dep.file_stem().and_then(OsStr::to_str)?;
+        let stem = stem.strip_prefix("lib").unwrap_or(stem);
+        let dep_info = fs::read_to_string(dep.with_file_name(format!("{}.d", stem))).ok()?;
+        let rule = dep_info.lines().next()?;
+        rule.split_once(": ").map(|(_, sources)| sources.to_string())
+    }
+
+    /// Warns about the crates there are several files of that the example
+    /// uses, according to `uses`, since the one it's compiled with may not
+    /// be the right one. Each is only warned about once, and the crates the
+    /// example doesn't use are only listed with
+    /// `SKEPTIC_WARN_AMBIGUOUS_DEPS`.
+    fn warn_ambiguous_deps<F>(deps_dir: &Path, deps: &Deps, mut uses: F)
+        where F: FnMut(&str) -> bool
+    {
+        static WARNED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+        let all = env_flag("SKEPTIC_WARN_AMBIGUOUS_DEPS");
+        let mut warned = lock(&WARNED);
+        for (name, dep) in &deps.libs {
+            if deps.ambiguous.contains(name) && !warned.contains(dep) && (all || uses(name)) {
+                warned.insert(dep.clone());
+                let _output = lock(&OUTPUT);
+                let _ = writeln!(io::stderr(),
+                                 "skeptic: warning: {} has several versions of crate {}, \
+                                  using the newest, {}",
+                                 deps_dir.display(),
+                                 name,
+                                 dep.display());
+            }
+        }
+    }
+
+    /// Whether `code` names the crate `name`, with `extern crate` or as the
+    /// start of a path.
+    fn uses_crate(code: &str, name: &str) -> bool {
+        code.match_indices(name).any(|(i, _)| {
+            let before = &code[..i];
+            let after = &code[i + name.len()..];
+            let is_ident = |c: char| c == '_' || c.is_alphanumeric();
+            if before.ends_with(is_ident) || after.starts_with(is_ident) {
+                return false;
+            }
+            // A path that starts with the crate, like `foo::x` or `::foo::x`,
+            // but not `bar::foo::x`
+            let starts_path = match before.trim_end().strip_suffix("::") {
+                Some(before) => !before.trim_end().ends_with(is_ident),
+                None => true,
+            };
+            before.trim_end().ends_with("extern crate") ||
+            (after.trim_start().starts_with("::") && starts_path)
+        })
+    }
+
+    /// The name of the crate a file in the deps directory is a library of,
+    /// e.g. `foo` for `libfoo-0123abcd.rlib`, and whether it's an rlib.
+    fn dep_crate_name(dep: &Path) -> Option<(String, bool)> {
+        let name = dep.file_stem().and_then(OsStr::to_str)?;
+        let ext = dep.extension().and_then(OsStr::to_str)?;
+        let libname = name.rsplitn(2, '-').nth(1)?;
+        match ext {
+            "rlib" | "so" | "dylib" if libname.starts_with("lib") => {
+                Some((libname[3..].to_string(), ext == "rlib"))
+            }
+            // Windows DLLs have no `lib` prefix
+            "dll" => Some((libname.to_string(), false)),
+            _ => None,
+        }
+    }
+
+    fn run_test_case(program_path: &Path,
+                     args: &[&str],
+                     outdir: &Path,
+                     options: &TestOptions)
+                     -> Result<(), RtError> {
+        let mut cmd = match runner(options) {
+            Some(runner) => {
+                let mut cmd = Command::new(&runner[0]);
+                cmd.args(&runner[1..]).arg(program_path);
+                cmd
+            }
+            None => Command::new(program_path),
+        };
+        // Leaks are only detected by default on some platforms
+        if sanitizer().as_deref() == Some("address") && env::var_os("ASAN_OPTIONS").is_none() {
+            cmd.env("ASAN_OPTIONS", "detect_leaks=1");
+        }
+        cmd.args(args).current_dir(options.current_dir.as_ref().map_or(outdir, |dir| dir));
+        let output = run_command(&mut cmd)?;
+        match options.exit_code {
+            Some(expected) if output.status.code() != Some(expected) => {
+                return Err(RtError::ExitCodeMismatch {
+                    command: shell_command(&cmd),
+                    expected,
+                    actual: output.status.code(),
+                    output,
+                });
+            }
+            Some(_) => {}
+            None if !output.status.success() => {
+                return Err(RtError::RunFailed {
+                    command: shell_command(&cmd),
+                    output,
+                });
+            }
+            None => {}
+        }
+        if let Some(ref expected) = options.expected_output {
+            let actual = String::from_utf8_lossy(&output.stdout);
+            if actual.trim_end() != expected.trim_end() {
+                return Err(RtError::OutputMismatch {
+                    expected: expected.trim_end().to_string(),
+                    actual: actual.trim_end().to_string(),
+                });
+            }
+        }
+        if let Some(ref expected) = options.expected_stderr {
+            let actual = String::from_utf8_lossy(&output.stderr);
+            if !actual.contains(expected.trim()) {
+                return Err(RtError::StderrMismatch {
+                    expected: expected.trim().to_string(),
+                    actual: actual.trim_end().to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// A line diff of the two texts, with removed lines prefixed by `-` and
+    /// added ones by `+`.
+    fn diff_lines(expected: &str, actual: &str) -> String {
+        let expected = expected.lines().collect::<Vec<_>>();
+        let actual = actual.lines().collect::<Vec<_>>();
+
+        // The lengths of the longest common subsequences of the suffixes
+        let mut lcs = vec![vec![0; actual.len() + 1]; expected.len() + 1];
+        for i in (0..expected.len()).rev() {
+            for j in (0..actual.len()).rev() {
+                lcs[i][j] = if expected[i] == actual[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+
+        let mut diff = String::new();
+        let (mut i, mut j) = (0, 0);
+        while i < expected.len() || j < actual.len() {
+            if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+                diff.push_str(&format!(" {}\n", expected[i]));
+                i += 1;
+                j += 1;
+            } else if j == actual.len() || (i < expected.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+                diff.push_str(&format!("-{}\n", expected[i]));
+                i += 1;
+            } else {
+                diff.push_str(&format!("+{}\n", actual[j]));
+                j += 1;
+            }
+        }
+        diff
+    }
+
+    /// The command as it would be typed in a POSIX shell, including the
+    /// working directory and environment it is run with.
+    fn shell_command(command: &Command) -> String {
+        let mut words = Vec::new();
+        if let Some(dir) = command.get_current_dir() {
+            words.push(format!("cd {} &&", shell_quote(dir.as_os_str())));
+        }
+        for (name, value) in command.get_envs() {
+            if let Some(value) = value {
+                words.push(format!("{}={}", name.to_string_lossy(), shell_quote(value)));
+            }
+        }
+        words.push(shell_quote(command.get_program()));
+        words.extend(command.get_args().map(shell_quote));
+        words.join(" ")
+    }
+
+    fn shell_quote(word: &OsStr) -> String {
+        let word = word.to_string_lossy();
+        let is_plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+        if !word.is_empty() && word.chars().all(is_plain) {
+            word.into_owned()
+        } else {
+            format!("'{}'", word.replace('\'', "'\\''"))
+        }
+    }
+
+    /// Runs the command, forwarding its output as it is printed, and
+    /// returns that output.
+    fn run_command(command: &mut Command) -> Result<Output, RtError> {
+        command_output(command, timeout(), true)
+    }
+
+    /// Runs the command and returns its output, without forwarding it.
+    fn collect_output(command: &mut Command) -> Result<Output, RtError> {
+        command_output(command, timeout(), false)
+    }
+
+    fn forward_output(stdout: &[u8], stderr: &[u8]) {
+        let _output = lock(&OUTPUT);
+        write!(io::stdout(),
+               "{}",
+               String::from_utf8_lossy(stdout))
+            .unwrap();
+        write!(io::stderr(),
+               "{}",
+               String::from_utf8_lossy(stderr))
+            .unwrap();
+    }
+
+    /// The number of warnings in the stderr of rustc, without the line
+    /// counting them.
+    fn count_warnings(stderr: &str) -> usize {
+        stderr.lines()
+            .filter(|line| line.starts_with("warning:") || line.starts_with("warning["))
+            .filter(|line| {
+                // Like `warning: 2 warnings emitted`
+                let mut words = line.strip_prefix("warning:").unwrap_or("").split_whitespace();
+                let count = words.next().map(|word| word.parse::<usize>().is_ok()).unwrap_or(false);
+                !(count && words.next().map(|word| word.starts_with("warning")).unwrap_or(false))
+            })
+            .count()
+    }
+
+    /// The example, e.g. `example readme_3 (README.md:42)`, for messages.
+    fn example_name(options: &TestOptions) -> String {
+        let name = match options.name {
+            Some(ref name) => format!("example {}", name),
+            None => String::from("the example"),
+        };
+        match options.location {
+            Some(ref location) => format!("{} ({})", name, location),
+            None => name,
+        }
+    }
+
+    /// The time limit for each command, from `SKEPTIC_TIMEOUT_SECS`, read
+    /// once.
+    fn timeout() -> Option<Duration> {
+        static TIMEOUT: Mutex<Option<Option<Duration>>> = Mutex::new(None);
+        *lock(&TIMEOUT).get_or_insert_with(|| parse_timeout(env::var("SKEPTIC_TIMEOUT_SECS").ok()))
+    }
+
+    /// The time limit given by `secs`, a number of seconds. 0 is no limit,
+    /// and a value that isn't a number is ignored, with a warning.
+    fn parse_timeout(secs: Option<String>) -> Option<Duration> {
+        let secs = secs?;
+        match secs.trim().parse() {
+            Ok(0) => None,
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => {
+                let _output = lock(&OUTPUT);
+                let _ = writeln!(io::stderr(),
+                                 "skeptic: warning: ignoring SKEPTIC_TIMEOUT_SECS={:?}, \
+                                  which isn't a number of seconds",
+                                 secs);
+                None
+            }
+        }
+    }
+
+    /// Like `Command::output`, but forwards each line the command prints to
+    /// the same stream as soon as it is printed if `forward` is set, and kills
+    /// the command and fails if it runs for longer than `timeout`.
+    fn command_output(command: &mut Command,
+                      timeout: Option<Duration>,
+                      forward: bool)
+                      -> Result<Output, RtError> {
+        let mut child = command.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Read the pipes while waiting so the child never blocks on a full one
+        fn read_all<R, W>(pipe: Option<R>, forward: Option<fn() -> W>) -> thread::JoinHandle<Vec<u8>>
+            where R: Read + Send + 'static,
+                  W: Write + 'static
+        {
+            thread::spawn(move || {
+                let mut buf = Vec::new();
+                if let Some(pipe) = pipe {
+                    let mut pipe = BufReader::new(pipe);
+                    loop {
+                        let start = buf.len();
+                        match pipe.read_until(b'\n', &mut buf) {
+                            Ok(0) | Err(_) => break,
+                            Ok(_) => {
+                                if let Some(forward) = forward {
+                                    // Lines of concurrent tests don't mix
+                                    let _output = lock(&OUTPUT);
+                                    let _ = forward().write_all(&buf[start..]);
+                                }
+                            }
+                        }
+                    }
+                }
+                buf
+            })
+        }
+        let stdout = read_all(child.stdout.take(), if forward { Some(io::stdout) } else { None });
+        let stderr = read_all(child.stderr.take(), if forward { Some(io::stderr) } else { None });
+
+        let status = match timeout {
+            None => child.wait()?,
+            Some(timeout) => {
+                let deadline = Instant::now() + timeout;
+                loop {
+                    if let Some(status) = child.try_wait()? {
+                        break status;
+                    }
+                    if Instant::now() >= deadline {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        return Err(RtError::TimedOut {
+                            command: shell_command(command),
+                            timeout,
+                        });
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        };
+
+        Ok(Output {
+            status,
+            stdout: stdout.join().unwrap(),
+            stderr: stderr.join().unwrap(),
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[should_panic(expected = "timed out after 1 seconds")]
+    fn test_output_with_timeout() {
+        let timeout = Some(Duration::from_secs(1));
+        if let Err(e) = command_output(Command::new("sleep").arg("10"), timeout, false) {
+            panic!("{}", e);
+        }
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout(Some(String::from("30"))), Some(Duration::from_secs(30)));
+        assert_eq!(parse_timeout(Some(String::from("0"))), None);
+        assert_eq!(parse_timeout(Some(String::from("soon"))), None);
+        assert_eq!(parse_timeout(Some(String::new())), None);
+        assert_eq!(parse_timeout(None), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_output_forwarded() {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("echo out; echo err >&2; printf partial");
+        let output = command_output(&mut cmd, None, true).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"out\npartial");
+        assert_eq!(output.stderr, b"err\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_output_with_timeout_finishes() {
+        let output = command_output(Command::new("echo").arg("hi"), Some(Duration::from_secs(10)), false)
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"hi\n");
+    }
+
+    #[test]
+    fn test_failure_message() {
+        let error = RtError::CompileSucceeded { command: String::from("rustc test.rs") };
+        let options = TestOptions {
+            name: Some(String::from("readme_3")),
+            location: Some(String::from("README.md:42")),
+            ..TestOptions::default()
+        };
+        assert_eq!(failure_message(&options, &error),
+                   format!("README.md:42: example readme_3 failed: {}", error));
+        let unnamed = TestOptions { name: None, ..options.clone() };
+        assert!(failure_message(&unnamed, &error).starts_with("README.md:42: the example failed: "));
+        let unlocated = TestOptions { location: None, ..options.clone() };
+        assert!(failure_message(&unlocated, &error).starts_with("example readme_3 failed: "));
+        assert_eq!(failure_message(&TestOptions::default(), &error), error.to_string());
+    }
+
+    #[test]
+    fn test_write_test_case_allows() {
+        let dir = TempDir::new("skeptic-test").unwrap();
+        let path = dir.path().join("test.rs");
+        let options = TestOptions {
+            location: Some(String::from("guide.md:3")),
+            allows: vec![String::from("dead_code"), String::from("unused_variables")],
+            ..TestOptions::default()
+        };
+        write_test_case(&path, "#![allow(unused)]\nfn main() {}\n", &options).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(),
+                   "/* guide.md:3 */ #![allow(dead_code, unused_variables)] \
+                    #![allow(unused)]\nfn main() {}\n");
+
+        let source = batch_source(&[("guide_0", "fn main() {}\n")], &options.allows);
+        assert!(source.starts_with("mod guide_0 {\n#![allow(dead_code, unused_variables)]\nfn main"),
+                "{}", source);
+        assert!(batch_source(&[("guide_0", "fn main() {}\n")], &[]).starts_with("mod guide_0 {\nfn main"));
+    }
+
+    #[test]
+    fn test_test_dir_name() {
+        let name = |options: &TestOptions| {
+            let dir = TestDir::new(options).unwrap();
+            dir.path().file_name().unwrap().to_string_lossy().into_owned()
+        };
+        let options = TestOptions {
+            name: Some(String::from("readme_0")),
+            crate_name: Some(String::from("foo")),
+            ..TestOptions::default()
+        };
+        assert!(name(&options).starts_with("rust-skeptic-foo-readme_0"));
+        assert!(name(&TestOptions::default()).starts_with("rust-skeptic"));
+    }
+
+    #[test]
+    fn test_keep_temp_on_failure() {
+        let new_dir = |keep_on_failure| {
+            TestDir {
+                dir: Some(TempDir::new("skeptic-test").unwrap()),
+                keep_on_failure,
+                failed: false,
+            }
+        };
+        let path_after_panic = |dir: TestDir| {
+            let path = dir.path().to_owned();
+            let result = thread::spawn(move || {
+                let _dir = dir;
+                panic!("test failed");
+            }).join();
+            assert!(result.is_err());
+            path
+        };
+
+        let kept = path_after_panic(new_dir(true));
+        assert!(kept.exists());
+        fs::remove_dir_all(&kept).unwrap();
+
+        assert!(!path_after_panic(new_dir(false)).exists());
+
+        let dir = new_dir(true);
+        let path = dir.path().to_owned();
+        drop(dir);
+        assert!(!path.exists());
+    }
+
+    /// A directory laid out like cargo's target directory, with an empty
+    /// `debug/deps` directory, and the `OUT_DIR` of a crate built in it.
+    #[cfg(test)]
+    pub fn fake_target_dir() -> (TempDir, String) {
+        let tmp = TempDir::new("skeptic-target").unwrap();
+        let debug = tmp.path().join("debug");
+        let out_dir = debug.join("build").join("foo-0123").join("out");
+        fs::create_dir_all(&out_dir).unwrap();
+        fs::create_dir_all(debug.join("deps")).unwrap();
+        let out_dir = out_dir.to_str().unwrap().to_string();
+        (tmp, out_dir)
+    }
+
+    #[test]
+    fn test_try_run_test() {
+        let (_tmp, ref out_dir) = fake_target_dir();
+        let options = TestOptions::default();
+
+        assert!(try_run_test(out_dir, "fn main() {}", &options).is_ok());
+        match try_run_test(out_dir, "fn main() { panic!(\"boom\") }", &options) {
+            Err(RtError::RunFailed { ref output, .. }) => {
+                assert!(String::from_utf8_lossy(&output.stderr).contains("boom"));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        match try_compile_test(out_dir, "fn main() { let x: u8 = \"\"; }", &options) {
+            Err(RtError::CompileFailed { ref command, .. }) => assert!(command.contains("rustc")),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_try_compile_test_fail() {
+        let (_tmp, ref out_dir) = fake_target_dir();
+        let options = TestOptions::default();
+
+        assert!(try_compile_test_fail(out_dir, "fn main() { let x: u8 = \"\"; }", &options).is_ok());
+        match try_compile_test_fail(out_dir, "fn main() {}", &options) {
+            Err(RtError::CompileSucceeded { .. }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_expected_output() {
+        let (_tmp, ref out_dir) = fake_target_dir();
+        let expecting = |output: &str| {
+            TestOptions { expected_output: Some(output.to_string()), ..TestOptions::default() }
+        };
+
+        let printing = "fn main() { println!(\"hi\"); }";
+        assert!(try_run_test(out_dir, printing, &expecting("hi\n\n")).is_ok());
+        match try_run_test(out_dir, printing, &expecting("bye")) {
+            Err(RtError::OutputMismatch { ref expected, ref actual }) => {
+                assert_eq!((&expected[..], &actual[..]), ("bye", "hi"));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_expected_stderr() {
+        let (_tmp, ref out_dir) = fake_target_dir();
+        let expecting_stderr = |stderr: &str| {
+            TestOptions { expected_stderr: Some(stderr.to_string()), ..TestOptions::default() }
+        };
+
+        let warning = "fn main() { eprintln!(\"warning: low on hugs\"); }";
+        assert!(try_run_test(out_dir, warning, &expecting_stderr("low on hugs\n")).is_ok());
+        match try_run_test(out_dir, warning, &expecting_stderr("error")) {
+            Err(RtError::StderrMismatch { ref expected, ref actual }) => {
+                assert_eq!((&expected[..], &actual[..]), ("error", "warning: low on hugs"));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let (_tmp, ref out_dir) = fake_target_dir();
+        let exiting_with = |code| TestOptions { exit_code: Some(code), ..TestOptions::default() };
+
+        let exiting = "fn main() { std::process::exit(2) }";
+        assert!(try_run_test(out_dir, exiting, &exiting_with(2)).is_ok());
+        match try_run_test(out_dir, exiting, &exiting_with(3)) {
+            Err(RtError::ExitCodeMismatch { expected: 3, actual: Some(2), .. }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match try_run_test(out_dir, "fn main() {}", &exiting_with(2)) {
+            Err(ref e @ RtError::ExitCodeMismatch { .. }) => {
+                assert!(e.to_string().starts_with("The example exited with code 0, but was expected \
+                                                   to exit with code 2:\n"), "{}", e);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(matches!(try_run_test(out_dir, exiting, &TestOptions::default()),
+                         Err(RtError::RunFailed { .. })));
+    }
+
+    #[test]
+    fn test_current_dir() {
+        let (tmp, ref out_dir) = fake_target_dir();
+        let in_tmp = TestOptions { current_dir: Some(tmp.path().to_owned()), ..TestOptions::default() };
+
+        let reading_deps = "fn main() { assert!(std::path::Path::new(\"debug/deps\").is_dir()); }";
+        assert!(try_run_test(out_dir, reading_deps, &in_tmp).is_ok());
+        assert!(try_run_test(out_dir, reading_deps, &TestOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_panic_abort() {
+        let (_tmp, ref out_dir) = fake_target_dir();
+        let aborting = TestOptions { panic_abort: true, ..TestOptions::default() };
+
+        let catching = "fn main() { assert!(std::panic::catch_unwind(|| panic!(\"boom\")).is_err()); }";
+        assert!(try_run_test(out_dir, catching, &TestOptions::default()).is_ok());
+        match try_run_test(out_dir, catching, &aborting) {
+            Err(RtError::RunFailed { ref command, .. }) => assert!(command.contains("out.exe")),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let (_tmp, ref out_dir) = fake_target_dir();
+        let checked = TestOptions { check: true, ..TestOptions::default() };
+
+        let hollow = "fn main() {\n    let answer = 42;\n}\nfn unused() {}\n";
+        assert!(try_compile_test(out_dir, hollow, &TestOptions::default()).is_ok());
+        match try_compile_test(out_dir, hollow, &checked) {
+            Err(RtError::CompileFailed { ref command, .. }) => {
+                assert!(command.contains("-D warnings"), "{}", command);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(try_compile_test(out_dir, "fn main() { println!(\"{}\", 42); }", &checked).is_ok());
+    }
+
+    #[test]
+    fn test_check_only() {
+        let (_tmp, ref out_dir) = fake_target_dir();
+        let check_only = TestOptions { check_only: true, ..TestOptions::default() };
+
+        // Only linking can find that the function doesn't exist
+        let unlinked = "extern \"C\" { fn skeptic_missing(); }\nfn main() { unsafe { skeptic_missing() } }";
+        assert!(try_compile_test(out_dir, unlinked, &check_only).is_ok());
+        assert!(try_compile_test(out_dir, unlinked, &TestOptions::default()).is_err());
+        match try_compile_test(out_dir, "fn main() { let x: u8 = \"\"; }", &check_only) {
+            Err(RtError::CompileFailed { ref command, .. }) => {
+                assert!(command.contains("--emit=metadata"), "{}", command);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_missing_extern() {
+        let (_tmp, ref out_dir) = fake_target_dir();
+        let needing_foo = TestOptions { externs: vec!["foo".to_string()], ..TestOptions::default() };
+
+        match try_compile_test(out_dir, "fn main() {}", &needing_foo) {
+            Err(RtError::MissingExtern { ref name, .. }) => assert_eq!(name, "foo"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let (_tmp, ref out_dir) = fake_target_dir();
+        let failing_with = |codes: &[&str]| {
+            TestOptions {
+                error_codes: codes.iter().map(|c| c.to_string()).collect(),
+                ..TestOptions::default()
+            }
+        };
+
+        let mismatched = "fn main() { let x: u8 = \"\"; }";
+        assert!(try_compile_test_fail(out_dir, mismatched, &failing_with(&["E0308"])).is_ok());
+        match try_compile_test_fail(out_dir, mismatched, &failing_with(&["E0308", "E0425"])) {
+            Err(RtError::MissingErrorCodes { ref missing, ref actual, .. }) => {
+                assert_eq!((missing.clone(), actual.clone()),
+                           (vec!["E0425".to_string()], vec!["E0308".to_string()]));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        match try_compile_test_fail(out_dir, "fn main() {}", &failing_with(&["E0308"])) {
+            Err(RtError::CompileSucceeded { .. }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cached_warnings() {
+        let tmp = TempDir::new("skeptic-cache").unwrap();
+        let cached = tmp.path().join("0123456789abcdef");
+        assert_eq!(cached_warnings(&cached), 0);
+        fs::write(tmp.path().join("0123456789abcdef.warnings"), "2").unwrap();
+        assert_eq!(cached_warnings(&cached), 2);
+    }
+
+    #[test]
+    fn test_rustc_version() {
+        let version = rustc_version(OsStr::new("rustc")).unwrap();
+        assert!(version.starts_with(b"rustc "), "{}", String::from_utf8_lossy(&version));
+        assert_eq!(rustc_version(OsStr::new("skeptic-no-such-rustc")), None);
+    }
+
+    #[test]
+    fn test_count_warnings() {
+        let stderr = "warning: unused variable: `x`\n --> test.rs:1:17\n\n\
+                      warning[E0170]: pattern binding `A` is named the same as a variant\n\n\
+                      warning: 2 warnings emitted\n";
+        assert_eq!(count_warnings(stderr), 2);
+        assert_eq!(count_warnings("warning: 1 warning emitted\n"), 0);
+        assert_eq!(count_warnings("error: expected `;`\n"), 0);
+        assert_eq!(count_warnings("warning"), 0);
+        assert_eq!(count_warnings("warning: 3 warnings\nwarning: é\n"), 1);
+
+        let options = TestOptions {
+            name: Some(String::from("readme_3")),
+            location: Some(String::from("README.md:42")),
+            ..TestOptions::default()
+        };
+        assert_eq!(example_name(&options), "example readme_3 (README.md:42)");
+        assert_eq!(example_name(&TestOptions::default()), "the example");
+    }
+
+    #[test]
+    fn test_json_string_field() {
+        let line = r#"{"message":"mismatched \"types\"","code":{"code":"E0308","explanation":"x"},"rendered":"error: a\\b\n\u00e9\ud83e\udd80\n"}"#;
+        assert_eq!(json_string_field(line, "message"), Some("mismatched \"types\"".to_string()));
+        assert_eq!(json_string_field(line, "code"), Some("E0308".to_string()));
+        assert_eq!(json_string_field(line, "rendered"), Some("error: a\\b\né\u{1f980}\n".to_string()));
+        assert_eq!(json_string_field(r#"{"code":null,"rendered":null}"#, "rendered"), None);
+    }
+
+    #[test]
+    fn test_compile_batch() {
+        static EXAMPLES: &[(&str, &str)] = &[
+            ("guide_0", "fn main() { println!(\"zero\"); }"),
+            ("guide_1", "use std::fmt;\nfn main() -> Result<(), fmt::Error> { Err(fmt::Error) }"),
+            ("guide_2", "fn main() { panic!(\"two\") }"),
+        ];
+        static BROKEN: &[(&str, &str)] = &[
+            ("guide_0", "fn main() {}"),
+            // Paths in `use` are relative to the crate root in Rust 2015
+            ("guide_1", "mod m { pub fn f() {} }\nuse m::f;\nfn main() { f() }"),
+        ];
+
+        let (tmp, ref out_dir) = fake_target_dir();
+        let options = TestOptions::default();
+
+        let batch = Batch { examples: EXAMPLES, index: 0 };
+        let binary = compile_batch(&batch, "rustc", out_dir, &options).unwrap();
+        assert_eq!(compile_batch(&batch, "rustc", out_dir, &options), Some(binary.clone()));
+        let run = |name: &str, options: &TestOptions| {
+            run_test_case(&binary, &[name], tmp.path(), options)
+        };
+        let expecting = TestOptions { expected_output: Some(String::from("zero")), ..options.clone() };
+        assert!(run("guide_0", &expecting).is_ok());
+        match run("guide_1", &options) {
+            Err(RtError::RunFailed { ref output, .. }) => {
+                assert!(String::from_utf8_lossy(&output.stderr).contains("Error"));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        match run("guide_2", &options) {
+            Err(RtError::RunFailed { ref output, .. }) => {
+                assert!(String::from_utf8_lossy(&output.stderr).contains("two"));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let broken = Batch { examples: BROKEN, index: 0 };
+        assert_eq!(compile_batch(&broken, "rustc", out_dir, &options), None);
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        assert_eq!(diff_lines("a\nb\nc", "a\nx\nc\nd"), " a\n-b\n+x\n c\n+d\n");
+        assert_eq!(diff_lines("same", "same"), " same\n");
+    }
+
+    #[test]
+    fn test_shell_command() {
+        let mut cmd = Command::new("rustc");
+        cmd.arg("test.rs")
+            .arg("--extern").arg("foo=/tmp/my deps/libfoo-0123.rlib")
+            .arg("--cfg").arg("feature=\"it's\"")
+            .arg("")
+            .env("RUST_BACKTRACE", "1")
+            .current_dir("/tmp");
+        assert_eq!(shell_command(&cmd),
+                   "cd /tmp && RUST_BACKTRACE=1 rustc test.rs \
+                    --extern 'foo=/tmp/my deps/libfoo-0123.rlib' \
+                    --cfg 'feature=\"it'\\''s\"' ''");
+    }
+
+    #[test]
+    fn test_add_sysroot() {
+        let args = |sysroot: Option<&str>| {
+            let mut cmd = Command::new("rustc");
+            add_sysroot(&mut cmd, sysroot.map(OsString::from));
+            cmd.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect::<Vec<_>>()
+        };
+        assert_eq!(args(Some("/opt/my sysroot")), ["--sysroot", "/opt/my sysroot"]);
+        assert!(args(Some("")).is_empty());
+        assert!(args(None).is_empty());
+    }
+
+    #[test]
+    fn test_add_sanitizer() {
+        let args = |host: Option<&str>| {
+            let mut cmd = Command::new("rustc");
+            add_sanitizer(&mut cmd, "address", host.map(String::from));
+            cmd.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect::<Vec<_>>()
+        };
+        assert_eq!(args(Some("x86_64-unknown-linux-gnu")),
+                   ["--target", "x86_64-unknown-linux-gnu", "-Zsanitizer=address"]);
+        assert_eq!(args(None), ["-Zsanitizer=address"]);
+    }
+
+    #[test]
+    fn test_runner_var() {
+        assert_eq!(runner_var("wasm32-wasip1"), "CARGO_TARGET_WASM32_WASIP1_RUNNER");
+        assert_eq!(runner_var("thumbv7em-none-eabihf"), "CARGO_TARGET_THUMBV7EM_NONE_EABIHF_RUNNER");
+        assert_eq!(runner_var("x86_64-unknown-linux-gnu.json"),
+                   "CARGO_TARGET_X86_64_UNKNOWN_LINUX_GNU_JSON_RUNNER");
+    }
+
+    #[test]
+    fn test_split_flags() {
+        assert_eq!(split_flags("  -C opt-level=0\t--cfg 'feature=\"x\"' "),
+                   ["-C", "opt-level=0", "--cfg", "feature=\"x\""]);
+        assert_eq!(split_flags(r#"--cfg feature=\"y\" -L "my dir" '' a\ b"#),
+                   ["--cfg", "feature=\"y\"", "-L", "my dir", "", "a b"]);
+        assert!(split_flags("").is_empty());
+    }
+
+    #[test]
+    fn test_dep_crate_name() {
+        let name = |path: &str| dep_crate_name(Path::new(path));
+        assert_eq!(name("deps/libfoo-0123abcd.rlib"), Some(("foo".to_string(), true)));
+        assert_eq!(name("deps/libfoo_derive-0123abcd.so"), Some(("foo_derive".to_string(), false)));
+        assert_eq!(name("deps/libfoo_derive-0123abcd.dylib"), Some(("foo_derive".to_string(), false)));
+        assert_eq!(name("deps/library_derive-0123abcd.dll"), Some(("library_derive".to_string(), false)));
+        assert_eq!(name("deps/libfoo-0123abcd.rmeta"), None);
+        assert_eq!(name("deps/libfoo-0123abcd.d"), None);
+        assert_eq!(name("deps/foo-0123abcd"), None);
+    }
+
+    #[test]
+    fn test_find_deps() {
+        let tmp = TempDir::new("skeptic-deps").unwrap();
+        let create = |name: &str, age: u64| {
+            let file = File::create(tmp.path().join(name)).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(age)).unwrap();
+        };
+        create("libfoo-0123.rlib", 100);
+        create("libfoo-4567.rlib", 10);
+        create("libfoo-89ab.rlib", 1000);
+        create("libbar_derive-0123.so", 0);
+        create("libfoo-0123.so", 0);
+        create("libfoo-0123.rmeta", 0);
+        // A build dependency and a dependency built from the same sources
+        create("libqux-0123.rlib", 0);
+        create("libqux-4567.rlib", 0);
+        let dep_info = |name: &str, sources: &str| {
+            let rule = format!("{}: {}\n", tmp.path().join(name).display(), sources);
+            File::create(tmp.path().join(name)).unwrap().write_all(rule.as_bytes()).unwrap();
+        };
+        dep_info("qux-0123.d", "src/lib.rs src/x.rs");
+        dep_info("qux-4567.d", "src/lib.rs src/x.rs");
+        dep_info("foo-0123.d", "foo-1.0.0/src/lib.rs");
+        dep_info("foo-4567.d", "foo-2.0.0/src/lib.rs");
+
+        let deps = find_deps(tmp.path()).unwrap();
+        assert_eq!(deps.libs,
+                   [("foo".to_string(), tmp.path().join("libfoo-4567.rlib")),
+                    ("qux".to_string(), tmp.path().join("libqux-4567.rlib")),
+                    ("bar_derive".to_string(), tmp.path().join("libbar_derive-0123.so"))]);
+        assert_eq!(deps.ambiguous.into_iter().collect::<Vec<_>>(), ["foo"]);
+
+        // The deps are only scanned once
+        let cached = cached_deps(tmp.path(), |_| false).unwrap();
+        assert_eq!(*cached, find_deps(tmp.path()).unwrap());
+        create("libbaz-0123.rlib", 0);
+        assert_eq!(cached_deps(tmp.path(), |_| false).unwrap(), cached);
+        assert_eq!(find_deps(tmp.path()).unwrap().libs.len(), 4);
+    }
+
+    #[test]
+    fn test_uses_crate() {
+        assert!(uses_crate("extern crate foo;", "foo"));
+        assert!(uses_crate("extern crate foo as bar;", "foo"));
+        assert!(uses_crate("use foo::Bar;", "foo"));
+        assert!(uses_crate("let x = ::foo::bar();", "foo"));
+        assert!(uses_crate("use {foo :: Bar};", "foo"));
+        assert!(!uses_crate("use foobar::Bar;", "foo"));
+        assert!(!uses_crate("use bar::foo::Baz;", "foo"));
+        assert!(!uses_crate("let foo = 1;", "foo"));
+        assert!(!uses_crate("// foo_bar::x", "foo"));
+    }
+
+    #[test]
+    fn test_find_target_dir() {
+        let tmp = TempDir::new("skeptic-target").unwrap();
+        let custom = tmp.path().join("custom-target");
+        let out_dir = tmp.path().join("elsewhere/debug/build/foo-0123/out");
+        fs::create_dir_all(custom.join("x86_64-unknown-linux-musl/debug/deps")).unwrap();
+        fs::create_dir_all(custom.join("debug/deps")).unwrap();
+
+        // Without CARGO_TARGET_DIR, the directory is relative to OUT_DIR
+        assert_eq!(find_target_dir(&out_dir, None, None),
+                   tmp.path().join("elsewhere/debug"));
+
+        assert_eq!(find_target_dir(&out_dir, Some(custom.clone()), None),
+                   custom.join("debug"));
+        assert_eq!(find_target_dir(&out_dir,
+                                   Some(custom.clone()),
+                                   Some("x86_64-unknown-linux-musl".to_string())),
+                   custom.join("x86_64-unknown-linux-musl/debug"));
+
+        // A layout that doesn't exist falls back to the OUT_DIR heuristic
+        assert_eq!(find_target_dir(&out_dir,
+                                   Some(custom.clone()),
+                                   Some("wasm32-unknown-unknown".to_string())),
+                   tmp.path().join("elsewhere/debug"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_windows_out_dir() {
+        let (tmp, ref out_dir) = fake_target_dir();
+        assert!(out_dir.contains('\\'));
+
+        assert_eq!(find_target_dir(Path::new(out_dir), None, None), tmp.path().join("debug"));
+        assert!(try_compile_test(out_dir, "fn main() {}", &TestOptions::default()).is_ok());
+    }
+}
+
+#[test]
+fn test_omitted_lines() {
+    let lines = [
+        "# use std::collections::BTreeMap as Map;\n",
+        "#\n",
+        "#[allow(dead_code)]\n",
+        "fn main() {\n",
+        "    let map = Map::new();\n",
+        "    #\n",
+        "    # let _ = map;\n",
+        "    ## not hidden\n",
+        "##[allow(unused)]\n",
+        "}\n",
+    ].concat();
+
+    let expected = [
+        "use std::collections::BTreeMap as Map;\n",
+        "\n",
+        "#[allow(dead_code)]\n",
+        "fn main() {\n",
+        "    let map = Map::new();\n",
+        "\n",
+        "let _ = map;\n",
+        "# not hidden\n",
+        "#[allow(unused)]\n",
+        "}\n",
+    ].concat();
+
+    assert_eq!(create_test_input(&lines), expected);
+}
+
+#[test]
+fn test_omitted_lines_crlf() {
+    let lines = [
+        "# use std::collections::BTreeMap as Map;\r\n",
+        "#\r\n",
+        "fn main() {\r\n",
+        "    #\r\n",
+        "    # let _ = Map::<u8, u8>::new();\r\n",
+        "    ## not hidden\r\n",
+        "}\r\n",
+        "#",
+    ].concat();
+
+    let expected = [
+        "use std::collections::BTreeMap as Map;\r\n",
+        "\r\n",
+        "fn main() {\r\n",
+        "\r\n",
+        "let _ = Map::<u8, u8>::new();\r\n",
+        "# not hidden\r\n",
+        "}\r\n",
+        "",
+    ].concat();
+
+    assert_eq!(create_test_input(&lines), expected);
+    assert!(is_hidden_line("#\r\n"));
+    assert!(is_hidden_line("  # let x = 1;\r\n"));
+    assert!(!is_hidden_line("##\r\n"));
+}
+
+#[test]
+fn test_raw_string_literal() {
+    assert_eq!(raw_string_literal("let x = 1;"), "r#\"let x = 1;\"#");
+    assert_eq!(raw_string_literal("let s = \"a\";"), "r#\"let s = \"a\";\"#");
+    assert_eq!(raw_string_literal("r###\"a\"###"), "r####\"r###\"a\"###\"####");
+    assert_eq!(raw_string_literal("\"#\"####\"##"), "r#####\"\"#\"####\"##\"#####");
+}
+
+/// The configuration generating the tests of `docs`, relative to `dir`,
+/// into `skeptic-tests.rs` in `dir`, with every option off.
+#[cfg(test)]
+fn test_config(dir: &Path, docs: &[&str]) -> Config {
+    Config {
+        out_dir: dir.to_owned(),
+        root_dir: dir.to_owned(),
+        out_file: dir.join("skeptic-tests.rs"),
+        docs: docs.iter().map(|doc| doc.to_string()).collect(),
+        module_per_doc: false,
+        default_template: None,
+        wrap_module: None,
+        test_unlabeled_blocks: false,
+        require_tests_per_doc: false,
+        transform: None,
+        ignore_cfg: None,
+        html_code_blocks: false,
+        json_manifest: false,
+        rerun_directives: false,
+        default_allows: Vec::new(),
+        crate_name: None,
+        crate_version: None,
+        target: None,
+        filter: None,
+        batch: false,
+    }
+}
+
+/// An untagged example on the first line of its document.
+#[cfg(test)]
+fn test_extracted(name: &str, text: &str) -> ExtractedTest {
+    ExtractedTest {
+        name: name.to_string(),
+        text: text.to_string(),
+        line: 1,
+        ignore: false,
+        ignore_reason: None,
+        no_run: false,
+        should_panic: false,
+        expected_panic: None,
+        compile_fail: false,
+        template: None,
+        edition: None,
+        expected_output: None,
+        expected_stderr: None,
+        externs: Vec::new(),
+        error_codes: Vec::new(),
+        cwd_manifest: false,
+        required_features: Vec::new(),
+        no_miri: false,
+        check: false,
+        panic_abort: false,
+        aux_files: Vec::new(),
+        check_only: false,
+        exit_code: None,
+    }
+}
+
+#[test]
+fn test_windows_out_dir() {
+    let out_dir = r##"C:\Users\me"#\target\debug\build\foo-0123\out"##;
+    let config = Config {
+        out_dir: PathBuf::from(out_dir),
+        root_dir: PathBuf::from(r"C:\Users\me"),
+        out_file: PathBuf::from(out_dir).join("skeptic-tests.rs"),
+        ..test_config(Path::new(""), &[])
+    };
+    let test = test_extracted("guide_0", "fn main() {}\n");
+    let test_text = create_test_text(&config, None, &test).unwrap();
+    let runner = create_test_runner(&config, &test, &test_text, "guide.md:1", None);
+    let literal = raw_string_literal(out_dir);
+    assert!(runner.contains(&format!("run_test_with({}, s,", literal)), "{}", runner);
+    assert!(runner.starts_with("#[test] fn guide_0()"), "{}", runner);
+
+    // The literal is the path, backslashes and all, once compiled
+    let (_tmp, ref rt_out_dir) = rt::fake_target_dir();
+    let check = format!("fn main() {{ assert_eq!({}, {:?}); }}", literal, out_dir);
+    rt::try_run_test(rt_out_dir, &check, &rt::TestOptions::default()).unwrap();
+}
+
+#[test]
+fn test_batches() {
+    let test = |name: &str, text: &str| (test_extracted(name, text), text.to_string());
+    let mut tests = vec![
+        test("guide_0", "fn main() {}\n"),
+        test("guide_1", "fn main() {}\n"),
+        test("guide_2", "fn main() {}\n"),
+        test("guide_3", "#![allow(unused)]\nfn main() {}\n"),
+        test("guide_4", "fn main() {}\n"),
+        test("guide_5", "fn main() {}\n"),
+        test("guide_6", "fn main() {}\n"),
+        test("guide_7", "fn main() {}\n"),
+    ];
+    tests[1].0.edition = Some(String::from("2018"));
+    tests[2].0.should_panic = true;
+    tests[4].0.no_run = true;
+    tests[5].0.ignore = true;
+    tests[6].0.compile_fail = true;
+    tests[7].0.edition = Some(String::from("2021"));
+    assert_eq!(batches(&tests), [vec![0, 2]]);
+    let extracted = tests.iter().map(|(test, _)| test.clone()).collect::<Vec<_>>();
+    assert_eq!(summary(&extracted), "skeptic: 8 examples: 5 run, 2 compiled-only, 1 ignored");
+    tests[2].0.required_features = vec![String::from("std")];
+    assert!(batches(&tests).is_empty());
+}
+
+#[test]
+fn test_emit_batches() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    File::create(dir.path().join("a.md")).unwrap()
+        .write_all(b"```rust\nfn main() {}\n```\n\n```rust\nfn main() {}\n```\n").unwrap();
+    let generated = |batch: bool| {
+        run(Config { batch, ..test_config(dir.path(), &["a.md"]) }).unwrap();
+        fs::read_to_string(dir.path().join("skeptic-tests.rs")).unwrap()
+    };
+    let unbatched = generated(false);
+    assert!(!unbatched.contains("SKEPTIC_BATCH_"), "{}", unbatched);
+    let batched = generated(true);
+    assert!(batched.contains("static SKEPTIC_BATCH_0_0: &[(&str, &str)] = &[\n    (\"a_0\", "),
+            "{}", batched);
+}
+
+#[test]
+fn test_split_crate_attributes() {
+    assert_eq!(split_crate_attributes("#![allow(unused)]\nlet x = 1;\n"),
+               ("#![allow(unused)]\n", "let x = 1;\n"));
+    assert_eq!(split_crate_attributes("// a comment\n#![feature(a,\n    b)]\n\n#![no_std]\nfn f() {}\n"),
+               ("// a comment\n#![feature(a,\n    b)]\n\n#![no_std]\n", "fn f() {}\n"));
+    assert_eq!(split_crate_attributes("let x = 1;\n#![allow(unused)]\n"),
+               ("", "let x = 1;\n#![allow(unused)]\n"));
+    assert_eq!(split_crate_attributes("#[derive(Debug)]\nstruct S;\n"),
+               ("", "#[derive(Debug)]\nstruct S;\n"));
+}
+
+#[test]
+fn test_wrap_in_main() {
+    let code = "fn main() {\n    let x = 1;\n}\n";
+    assert_eq!(wrap_in_main(code), code);
+
+    assert_eq!(wrap_in_main("let x = 1;\nprintln!(\"{}\", x);"),
+               "fn main() { let x = 1;\nprintln!(\"{}\", x);\n}\n");
+    assert_eq!(wrap_in_main("use std::fmt;\n"), "use std::fmt;\nfn main() {\n}\n");
+    assert_eq!(wrap_in_main("mod helpers;\nhelpers::help();\n"),
+               "mod helpers;\nfn main() { helpers::help();\n}\n");
+    assert_eq!(wrap_in_main("mod helpers {}\n"), "fn main() { mod helpers {}\n}\n");
+
+    assert_eq!(wrap_in_main("let x: u8 = \"1\".parse()?;\n"),
+               "fn main() -> Result<(), Box<dyn std::error::Error>> { \
+                let x: u8 = \"1\".parse()?;\nOk(())\n}\n");
+    assert_eq!(wrap_in_main("let x: u8 = \"1\".parse()?;\nOk::<(), std::num::ParseIntError>(())\n"),
+               "fn main() -> Result<(), Box<dyn std::error::Error>> { \
+                let x: u8 = \"1\".parse()?;\nOk::<(), std::num::ParseIntError>(())\n}\n");
+
+    let code = [
+        "#[macro_use]\n",
+        "extern crate foo;\n",
+        "use std::collections::{\n",
+        "    HashMap,\n",
+        "};\n",
+        "\n",
+        "#[derive(Debug)]\n",
+        "struct Foo;\n",
+        "let _ = HashMap::<Foo, ()>::new();\n",
+    ].concat();
+    let expected = [
+        "#[macro_use]\n",
+        "extern crate foo;\n",
+        "use std::collections::{\n",
+        "    HashMap,\n",
+        "};\n",
+        "fn main() { \n",
+        "#[derive(Debug)]\n",
+        "struct Foo;\n",
+        "let _ = HashMap::<Foo, ()>::new();\n",
+        "}\n",
+    ].concat();
+    assert_eq!(wrap_in_main(&code), expected);
+}
+
+#[test]
+fn test_uses_question_mark() {
+    assert!(uses_question_mark("let x = f()?;"));
+    assert!(uses_question_mark("let x = f()?.g()?;"));
+    assert!(!uses_question_mark("let x = f();"));
+    assert!(!uses_question_mark("fn f() -> Option<u8> { g()? }"));
+    assert!(!uses_question_mark("println!(\"what?\"); // why?\n"));
+    assert!(!uses_question_mark("let s = r#\"\"?\"#; let c = '?'; let d = '\\'';"));
+    assert!(!uses_question_mark("/* f()? */ struct S<T: ?Sized>(Box<T>);"));
+    assert!(uses_question_mark("fn f<'a>(x: &'a str) {}\nlet y = f(\"\")?;"));
+}
+
+#[test]
+fn test_line_numbers_preserved() {
+    // Every line of the example, hidden or not, is on the same line of the
+    // code rustc compiles.
+    let code = [
+        "# use std::fmt;\n",
+        "#\n",
+        "## not hidden\n",
+        "let x = 1;\n",
+        "    # let y = x;\n",
+        "assert_eq!(x, 1);\n",
+    ].concat();
+    let input = create_test_input(&code);
+    assert_eq!(input.lines().count(), code.lines().count());
+
+    let wrapped = wrap_in_main(&input);
+    let lines = wrapped.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), code.lines().count() + 1);
+    assert_eq!(lines[3], "let x = 1;");
+    assert_eq!(lines[5], "assert_eq!(x, 1);");
+    assert_eq!(lines[6], "}");
+}
+
+#[test]
+fn test_should_panic_expected() {
+    let info = parse_code_block_info(r#"rust,should_panic(expected="say "hi", then (leave)"),no_run"#);
+    assert!(info.is_rust);
+    assert!(info.should_panic);
+    assert!(info.no_run);
+    assert_eq!(info.expected_panic, Some(r#"say "hi", then (leave)"#.to_string()));
+
+    let info = parse_code_block_info("rust,should_panic");
+    assert!(info.should_panic);
+    assert_eq!(info.expected_panic, None);
+}
+
+#[test]
+fn test_ignore_reason() {
+    let info = parse_code_block_info("rust,ignore(needs network),no_run");
+    assert!(info.ignore);
+    assert!(info.no_run);
+    assert_eq!(info.ignore_reason, Some("needs network".to_string()));
+
+    let info = parse_code_block_info(r#"rust,ignore("not "yet" done")"#);
+    assert_eq!(info.ignore_reason, Some(r#"not "yet" done"#.to_string()));
+
+    let info = parse_code_block_info("rust,ignore");
+    assert!(info.ignore);
+    assert_eq!(info.ignore_reason, None);
+}
+
+#[test]
+fn test_module_name() {
+    assert_eq!(module_name(Path::new("README.md")), "readme");
+    assert_eq!(module_name(Path::new("getting-started.md")), "getting_started");
+    assert_eq!(module_name(Path::new("docs/getting-started.md")), "docs_getting_started");
+    assert_eq!(module_name(Path::new("type.md")), "r#type");
+    assert_eq!(module_name(Path::new("self.md")), "self_");
+}
+
+#[test]
+fn test_expand_template() {
+    let snippet = Snippet { before: "", code: "let x = 1;", after: "" };
+    let expand = |template: &str| {
+        expand_template("`skt-foo`", template, &snippet, &[("test_name", "doc_0")])
+    };
+    assert_eq!(expand("fn main() {{ {} }}").unwrap(), "fn main() { let x = 1; }");
+    assert_eq!(expand("fn main() {{ {snippet} }} // {test_name}").unwrap(),
+               "fn main() { let x = 1; } // doc_0");
+    assert_eq!(expand("{} {}").unwrap(), "let x = 1; let x = 1;");
+
+    let snippet = Snippet { before: "setup();\n", code: "run();\n", after: "check();\n" };
+    let expand = |template: &str| {
+        expand_template("`skt-foo`", template, &snippet, &[("test_name", "doc_0")])
+    };
+    assert_eq!(expand("fn main() {{\n{}}}").unwrap(), "fn main() {\nsetup();\nrun();\ncheck();\n}");
+    assert_eq!(expand("{before}fn main() {{\n{}{after}}}").unwrap(),
+               "setup();\nfn main() {\nrun();\ncheck();\n}");
+    assert_eq!(expand("fn main() {{\n{}}} {{before}}").unwrap(),
+               "fn main() {\nsetup();\nrun();\ncheck();\n} {before}");
+
+    let err = expand("fn main() {{ {snipet} }}").unwrap_err();
+    assert_eq!(err.to_string(), "template `skt-foo`: unknown placeholder `{snipet}`");
+    assert!(expand("fn main() { {} }}").is_err());
+
+    let vars = [("test_name", "doc_0"), ("crate_name", "foo"), ("crate_version", "1.2.3")];
+    assert_eq!(expand_template("`skt-foo`", "// {crate_name} {crate_version} {{crate_name}}\n{}",
+                               &snippet, &vars).unwrap(),
+               "// foo 1.2.3 {crate_name}\nsetup();\nrun();\ncheck();\n");
+}
+
+/// Extracts the tests of `markdown`, as if it were the contents of
+/// `guide.md`, without writing it anywhere.
+#[cfg(test)]
+fn extract_from_markdown(markdown: &str) -> Result<DocTest, SkepticError> {
+    let path = Path::new("guide.md");
+    extract_tests_from_contents(path, markdown, path, false, false)
+}
+
+#[test]
+fn test_template_not_found() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    let path = dir.path().join("guide.md");
+    File::create(&path).unwrap().write_all(b"\n```rust,skt-fo\nx\n```\n").unwrap();
+    File::create(dir.path().join("guide.md.skt.md")).unwrap()
+        .write_all(b"```rust,skt-foo\n{}\n```\n\n```rust,skt-bar\n{}\n```\n").unwrap();
+
+    let doc_test = extract_tests_from_file(&path, Path::new("guide.md"), false, false).unwrap();
+    let err = template_not_found(&doc_test, &doc_test.tests[0], "fo");
+    assert_eq!(err.to_string(),
+               format!("{}:3: template `skt-fo` not found for test guide_0, \
+                        available templates are `skt-bar`, `skt-foo`",
+                       path.display()));
+}
+
+#[test]
+fn test_missing_doc() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    let path = dir.path().join("missing.md");
+    match extract_tests_from_path(&path) {
+        Err(SkepticError::Io { path: ref err_path, ref error }) => {
+            assert_eq!(err_path, &path);
+            assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn test_inline_template() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    let path = dir.path().join("guide.md");
+    File::create(&path).unwrap().write_all(b"\
+        ```rust,skeptic-template-name=foo\nfn main() {{ {} }}\n```\n\n\
+        ```rust,skt-foo\nlet x = 1;\n```\n").unwrap();
+    File::create(dir.path().join("guide.md.skt.md")).unwrap()
+        .write_all(b"```rust,skt-foo\nfile foo\n```\n\n```rust,skt-bar\nfile bar\n```\n").unwrap();
+
+    let doc_test = extract_tests_from_file(&path, Path::new("guide.md"), false, false).unwrap();
+    assert_eq!(doc_test.tests.len(), 1);
+    assert_eq!(doc_test.tests[0].name, "guide_0");
+    assert_eq!(doc_test.templates["foo"], "fn main() {{ {} }}\n");
+    assert_eq!(doc_test.templates["bar"], "file bar\n");
+}
+
+#[test]
+fn test_expect_output() {
+    let tests = extract_from_markdown("\
+        ```rust,expect_output\nfn main() { println!(\"hi\"); }\n```\n\n\
+        ```text\nhi\n```\n\n\
+        ```rust\nfn main() {}\n```\n").unwrap().tests;
+    assert_eq!(tests.len(), 2);
+    assert_eq!(tests[0].expected_output, Some("hi\n".to_string()));
+    assert_eq!(tests[1].expected_output, None);
+
+    let err = extract_from_markdown("```rust,expect_output\nfn main() {}\n```\n\n\
+                                     ```rust\nfn main() {}\n```\n").unwrap_err();
+    assert_eq!(err.to_string(),
+               "guide.md:2: test guide_0 is marked `expect_output`, but isn't followed by \
+                a code block with its output");
+}
+
+#[test]
+fn test_segments() {
+    // The segments keep their lines, with the setup shared
+    let tests = extract_from_markdown("\
+        ```rust\n\
+        let v = vec![1, 2];\n\
+        // ---\n\
+        assert_eq!(v.len(), 2);\n\
+        // ---\n\
+        assert_eq!(v[0], 1);\n\
+        assert_eq!(v[1], 2);\n\
+        ```\n\n\
+        ```rust\n\
+        let x = 1;\n\
+        ```\n").unwrap().tests;
+    let names: Vec<_> = tests.iter().map(|t| &t.name[..]).collect();
+    assert_eq!(names, ["guide_0_1", "guide_0_2", "guide_1"]);
+    assert_eq!(tests[0].text, "let v = vec![1, 2];\n\nassert_eq!(v.len(), 2);\n");
+    assert_eq!(tests[1].text, "let v = vec![1, 2];\n\n\n\nassert_eq!(v[0], 1);\nassert_eq!(v[1], 2);\n");
+    assert_eq!(tests[0].line, 2);
+    assert_eq!(tests[1].line, 2);
+    assert_eq!(split_segments("let x = 1;\n"), None);
+
+    let err = extract_from_markdown("\
+        ```rust,expect_output\n\
+        // ---\n\
+        println!(\"1\");\n\
+        ```\n\n\
+        ```text\n\
+        1\n\
+        ```\n").unwrap_err().to_string();
+    assert!(err.contains("is split with `// ---`"), "{}", err);
+}
+
+#[test]
+fn test_duplicate_templates() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    let path = dir.path().join("guide.md");
+    File::create(&path).unwrap().write_all(b"```rust,skt-foo\nfn main() {}\n```\n").unwrap();
+    File::create(dir.path().join("guide.skt.md")).unwrap()
+        .write_all(b"```rust,skt-foo\nfn main() {{ {} }}\n```\n\n\
+                     ```rust,skt-bar\n{}\n```\n\n\
+                     ```rust,skt-foo\n{}\n```\n").unwrap();
+    let err = extract_tests_from_path(&path).unwrap_err().to_string();
+    assert!(err.ends_with("guide.skt.md:10: template `skt-foo` is already defined on line 2"),
+            "{}", err);
+}
+
+#[test]
+fn test_aux_files() {
+    let info = parse_code_block_info("rust,aux=aux/a.rs,aux=b.rs ignore");
+    assert_eq!(info.aux_files, ["aux/a.rs", "b.rs"]);
+    assert!(info.ignore);
+
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    let path = dir.path().join("guide.md");
+    File::create(dir.path().join("helpers.rs")).unwrap().write_all(b"pub fn one() -> u8 { 1 }\n")
+        .unwrap();
+    File::create(&path).unwrap()
+        .write_all(b"```rust,aux=helpers.rs\nmod helpers;\n```\n").unwrap();
+    let tests = extract_tests_from_path(&path).unwrap();
+    assert_eq!(tests[0].aux_files, [dir.path().join("helpers.rs")]);
+
+    // The tests are regenerated when the files included or copied change
+    File::create(dir.path().join("other.md")).unwrap()
+        .write_all(b"```rust,aux=helpers.rs\n{{#include setup.rs}}\n```\n").unwrap();
+    File::create(dir.path().join("setup.rs")).unwrap().write_all(b"mod helpers;\n").unwrap();
+    let suite = extract_tests(dir.path(), &[String::from("guide.md"), String::from("other.md")],
+                              false, false, false).unwrap();
+    assert_eq!(suite.inputs(), [dir.path().join("helpers.rs"), dir.path().join("setup.rs")]);
+
+    File::create(&path).unwrap()
+        .write_all(b"```rust,aux=missing.rs\nmod missing;\n```\n").unwrap();
+    let err = extract_tests_from_path(&path).unwrap_err().to_string();
+    assert!(err.ends_with(&format!("guide.md:2: test guide_0 has `aux=missing.rs`, but {} \
+                                    isn't a file",
+                                   dir.path().join("missing.rs").display())),
+            "{}", err);
+}
+
+#[test]
+fn test_byte_order_mark() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    let path = dir.path().join("guide.md");
+    File::create(&path).unwrap()
+        .write_all(b"\xef\xbb\xbf```rust,skt-foo\nlet x = 1;\n```\n").unwrap();
+    File::create(dir.path().join("guide.skt.md")).unwrap()
+        .write_all(b"\xef\xbb\xbf```rust,skt-foo\nfn main() {{ {} }}\n```\n").unwrap();
+    let tests = extract_tests_from_path(&path).unwrap();
+    assert_eq!(tests.len(), 1);
+    assert_eq!(tests[0].text, "let x = 1;\n");
+    assert_eq!(tests[0].line, 2);
+    assert_eq!(load_templates(&path).unwrap()["foo"], "fn main() {{ {} }}\n");
+}
+
+#[test]
+fn test_create_test_input_unchanged() {
+    // The implementation it replaced, which collected the lines
+    let collected = |text: &str| -> String {
+        text.split_inclusive('\n').map(clean_omitted_line).collect()
+    };
+    let mut large = String::new();
+    for i in 0..10_000 {
+        large.push_str(&format!("# let hidden_{} = {};\r\n##[derive(Debug)]\n    let x = {};\n#\n", i, i, i));
+    }
+    let texts = ["", "#", "# ", "fn main() {}", "# fn main() {\n#     let x = 1;\n# }\n",
+                 "  # indented\n##escaped\n#not hidden\n", "é # ü\r\n#\r\n", &large];
+    for text in texts.iter() {
+        let input = create_test_input(text);
+        assert_eq!(input.as_bytes(), collected(text).as_bytes());
+    }
+}
+
+#[test]
+fn test_line_counter() {
+    let mut line_counter = LineCounter::default();
+    let s = "a\nb\n\nc";
+    assert_eq!(line_counter.line_at(s, 0), 1);
+    assert_eq!(line_counter.line_at(s, 2), 2);
+    assert_eq!(line_counter.line_at(s, 2), 2);
+    assert_eq!(line_counter.line_at(s, 5), 4);
+}
+
+#[test]
+fn test_block_quotes() {
+    // The quote markers are not part of the code, and the lines are those
+    // of the document
+    let tests = extract_from_markdown("\
+        > An aside:\n\
+        >\n\
+        > ```rust\n\
+        > let x = 1;\n\
+        >\n\
+        > assert_eq!(x, 1);\n\
+        > ```\n\n\
+        > > ```rust\n\
+        > > let y = 2;\n\
+        > > ```\n").unwrap().tests;
+    assert_eq!(tests.len(), 2);
+    assert_eq!(tests[0].text, "let x = 1;\n\nassert_eq!(x, 1);\n");
+    assert_eq!(tests[0].line, 4);
+    assert_eq!(tests[1].text, "let y = 2;\n");
+    assert_eq!(tests[1].line, 10);
+    assert_eq!(create_test_input(&tests[0].text), "let x = 1;\n\nassert_eq!(x, 1);\n");
+}
+
+#[test]
+fn test_expect_stderr() {
+    let tests = extract_from_markdown("\
+        ```rust,expect_output,expect_stderr\nfn main() {}\n```\n\n\
+        ```text\nout\n```\n\n\
+        ```text\nerr\n```\n\n\
+        ```rust,expect_stderr\nfn main() {}\n```\n\n\
+        ```text\nwarning\n```\n").unwrap().tests;
+    assert_eq!(tests.len(), 2);
+    assert_eq!(tests[0].expected_output, Some("out\n".to_string()));
+    assert_eq!(tests[0].expected_stderr, Some("err\n".to_string()));
+    assert_eq!(tests[1].expected_output, None);
+    assert_eq!(tests[1].expected_stderr, Some("warning\n".to_string()));
+
+    let err = extract_from_markdown("```rust,expect_output,expect_stderr\nfn main() {}\n```\n\n\
+                                     ```text\nout\n```\n").unwrap_err();
+    assert!(err.to_string().contains("is marked `expect_stderr`, but isn't followed"), "{}", err);
+}
+
+#[test]
+fn test_no_run_should_panic() {
+    let err = extract_from_markdown("```rust\nfn main() {}\n```\n\n\
+                                     ```rust,no_run,should_panic\nfn main() {}\n```\n")
+        .unwrap_err().to_string();
+    assert!(err.starts_with("guide.md:6: test guide_1 is marked both `no_run` and `should_panic`"),
+            "{}", err);
+}
+
+#[test]
+fn test_compile_fail_should_panic() {
+    let err = extract_from_markdown("```rust,compile_fail,should_panic\nfn main() {}\n```\n")
+        .unwrap_err().to_string();
+    assert_eq!(err, "guide.md:2: test guide_0 is marked both `compile_fail` and `should_panic`, \
+                     which is meaningless");
+}
+
+#[test]
+fn test_write_if_contents_changed() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    let path = dir.path().join("skeptic-tests.rs");
+    write_if_contents_changed(&path, "one").unwrap();
+    let modified = fs::metadata(&path).unwrap().modified().unwrap();
+    write_if_contents_changed(&path, "one").unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+    write_if_contents_changed(&path, "two").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "two");
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn test_expand_includes() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    let path = dir.path().join("guide.md");
+    File::create(dir.path().join("setup.rs")).unwrap().write_all(b"let a = 1;\nlet b = 2;").unwrap();
+
+    let code = "fn main() {\n    # {{#include setup.rs}}\n    {{#include setup.rs}}\n}\n";
+    let mut included = Vec::new();
+    assert_eq!(expand_includes(&path, 3, code, &mut included).unwrap(),
+               "fn main() {\n# let a = 1;\n# let b = 2;\nlet a = 1;\nlet b = 2;\n}\n");
+    assert_eq!(included, [dir.path().join("setup.rs"), dir.path().join("setup.rs")]);
+
+    let err = expand_includes(&path, 3, "{{#include missing.rs}}\n", &mut included).unwrap_err();
+    assert!(err.to_string().starts_with(&format!("{}:3: failed to include {}: ",
+                                                 path.display(),
+                                                 dir.path().join("missing.rs").display())));
+}
+
+#[test]
+fn test_matches_filter() {
+    let doc = Path::new("docs/chapter-3.md");
+    assert!(matches_filter("chapter_3_1", "chapter_3_1", doc));
+    assert!(matches_filter("chapter-3", "chapter_3_1", doc));
+    assert!(!matches_filter("chapter-4", "chapter_3_1", doc));
+    assert!(matches_filter("docs/*.md", "chapter_3_1", doc));
+    assert!(matches_filter("chapter_?_1", "chapter_3_1", doc));
+    assert!(!matches_filter("chapter_*_2", "chapter_3_1", doc));
+    assert!(glob_matches("a*b*c", "axxbyyc"));
+    assert!(glob_matches("*", ""));
+    assert!(!glob_matches("a*b", "axxbc"));
+}
+
+#[test]
+fn test_is_rust() {
+    let is_rust = |info: &str| parse_code_block_info(info).is_rust;
+    assert!(is_rust("rust"));
+    assert!(is_rust("rs"));
+    assert!(is_rust("rust,ignore"));
+    assert!(is_rust("rust,json"));
+    assert!(is_rust("json,rust"));
+    assert!(!is_rust("json"));
+    assert!(!is_rust("ignore"));
+    assert!(!is_rust("skt-foo"));
+    assert!(!is_rust(""));
+    assert!(!is_rust("  "));
+}
+
+#[test]
+fn test_space_separated_info() {
+    let same = |spaced: &str, commas: &str| {
+        assert_eq!(parse_code_block_info(spaced), parse_code_block_info(commas));
+    };
+    same("rust ignore no_run", "rust,ignore,no_run");
+    same("rust  skt-foo", "rust,skt-foo");
+    same("rust\tshould_panic", "rust,should_panic");
+    same("rust, no_run, edition2018", "rust,no_run,edition2018");
+    same("rust ignore(needs a network) no_run", "rust,ignore(needs a network),no_run");
+    same(r#"rust should_panic(expected = "a, b") skt-foo"#,
+         r#"rust,should_panic(expected = "a, b"),skt-foo"#);
+
+    let info = parse_code_block_info("rust ignore(needs a network) skt-foo");
+    assert_eq!(info.ignore_reason, Some("needs a network".to_string()));
+    assert_eq!(info.template, Some("foo".to_string()));
+}
+
+#[test]
+fn test_pandoc_attributes() {
+    let same = |pandoc: &str, commas: &str| {
+        assert_eq!(parse_code_block_info(pandoc), parse_code_block_info(commas));
+    };
+    same("{.rust}", "rust");
+    same("{.rust .ignore}", "rust,ignore");
+    same("{.rust .no_run}", "rust,no_run");
+    same("{ .rust .should_panic .edition2018 }", "rust,should_panic,edition2018");
+    same("{.rust .skt-foo .aux=aux/a.rs}", "rust,skt-foo,aux=aux/a.rs");
+    assert!(parse_code_block_info("{.rust}").is_rust);
+    assert!(!parse_code_block_info("{.python}").is_rust);
+
+    let tests = extract_from_markdown("```{.rust .no_run}\nfn main() {}\n```\n\n\
+                                       ```{.text}\nnot rust\n```\n").unwrap().tests;
+    assert_eq!(tests.len(), 1);
+    assert!(tests[0].no_run && !tests[0].ignore);
+}
+
+#[test]
+fn test_template_paths() {
+    assert_eq!(template_paths(Path::new("docs/guide.markdown")),
+               [PathBuf::from("docs/guide.markdown.skt.md"),
+                PathBuf::from("docs/guide.skt.md"),
+                PathBuf::from("docs/guide.skt.markdown")]);
+    assert_eq!(template_paths(Path::new("README.md")),
+               [PathBuf::from("README.md.skt.md"), PathBuf::from("README.skt.md")]);
+
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    let path = dir.path().join("guide.markdown");
+    File::create(dir.path().join("guide.skt.markdown")).unwrap()
+        .write_all(b"```rust,skt-foo\nfoo\n```\n").unwrap();
+    assert_eq!(load_templates(&path).unwrap()["foo"], "foo\n");
+    File::create(dir.path().join("guide.skt.md")).unwrap()
+        .write_all(b"```rust,skt-foo\nbar\n```\n").unwrap();
+    assert_eq!(load_templates(&path).unwrap()["foo"], "bar\n");
+}
+
+#[test]
+fn test_list_tests() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    File::create(dir.path().join("b.md")).unwrap()
+        .write_all(b"```rust,no_run\nfn main() {}\n```\n\n```rust,skt-foo\nx\n```\n").unwrap();
+    File::create(dir.path().join("a.md")).unwrap()
+        .write_all(b"```rust,ignore\nfn main() {}\n```\n").unwrap();
+
+    let b = dir.path().join("b.md");
+    let a = dir.path().join("a.md");
+    let tests = list_tests(&[b.to_str().unwrap(), a.to_str().unwrap()]).unwrap();
+    let summary = tests.iter()
+        .map(|t| (&t.name[..], &t.document, t.line, t.ignore, t.no_run, t.template.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(summary,
+               [("a_0", &a, 2, true, false, None),
+                ("b_0", &b, 2, false, true, None),
+                ("b_1", &b, 6, false, false, Some("foo".to_string()))]);
+}
+
+#[test]
+fn test_required_features() {
+    let info = parse_code_block_info("rust,requires-feature=async,requires-feature=serde");
+    assert!(info.is_rust);
+    assert_eq!(info.required_features, ["async", "serde"]);
+
+    let config = test_config(Path::new("out"), &[]);
+    let gated = |features: &[&str]| {
+        let test = ExtractedTest {
+            required_features: features.iter().map(|f| f.to_string()).collect(),
+            ..test_extracted("guide_0", "fn main() {}\n")
+        };
+        let test_text = create_test_text(&config, None, &test).unwrap();
+        create_test_runner(&config, &test, &test_text, "guide.md:1", None)
+    };
+    assert!(gated(&[]).starts_with("#[test]"));
+    assert!(gated(&["a"]).starts_with("#[cfg(feature = \"a\")]\n#[test]"));
+    assert!(gated(&["a", "b"])
+        .starts_with("#[cfg(all(feature = \"a\", feature = \"b\"))]\n#[test]"));
+}
+
+#[test]
+fn test_no_miri() {
+    assert!(parse_code_block_info("rust,no_miri").no_miri);
+    assert!(!parse_code_block_info("rust").no_miri);
+
+    let config = test_config(Path::new("out"), &[]);
+    let runner = |no_miri| {
+        let test = ExtractedTest { no_miri, ..test_extracted("guide_0", "fn main() {}\n") };
+        let test_text = create_test_text(&config, None, &test).unwrap();
+        create_test_runner(&config, &test, &test_text, "guide.md:1", None)
+    };
+    assert!(runner(true).starts_with("#[cfg_attr(miri, ignore)]\n#[test]"), "{}", runner(true));
+    assert!(!runner(false).contains("miri"));
+}
+
+#[test]
+fn test_ignore_cfg() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    File::create(dir.path().join("a.md")).unwrap()
+        .write_all(b"```rust\nfn main() {}\n```\n\n```rust,no_miri\nfn main() {}\n```\n\n\
+                     ```rust,ignore\nfn main() {}\n```\n").unwrap();
+    let config = Config {
+        ignore_cfg: Some(String::from("tarpaulin")),
+        ..test_config(dir.path(), &["a.md"])
+    };
+    run(config).unwrap();
+    let generated = fs::read_to_string(dir.path().join("skeptic-tests.rs")).unwrap();
+    assert!(generated.contains("#[cfg_attr(tarpaulin, ignore)]\n#[test] fn a_0()"), "{}", generated);
+    assert!(generated.contains("#[cfg_attr(any(miri, tarpaulin), ignore)]\n#[test] fn a_1()"),
+            "{}", generated);
+    assert!(generated.contains("#[ignore]\n#[test] fn a_2()"), "{}", generated);
+}
+
+#[test]
+fn test_exit_code() {
+    let error = |info: &str| {
+        extract_from_markdown(&format!("```{}\nfn main() {{}}\n```\n", info))
+            .unwrap_err().to_string()
+    };
+    let tests = extract_from_markdown("```rust,exit_code=2\nfn main() { std::process::exit(2) }\n```\n")
+        .unwrap().tests;
+    assert_eq!(tests[0].exit_code, Some(2));
+
+    let prefix = "guide.md:2: test guide_0 has ";
+    assert_eq!(error("rust,exit_code=two"),
+               format!("{}`exit_code=two`, which isn't a number", prefix));
+    assert_eq!(error("rust,exit_code=2,no_run"), format!("{}`exit_code=2`, but isn't run", prefix));
+    assert_eq!(error("rust,exit_code=2,should_panic"),
+               format!("{}`exit_code=2`, but is expected to panic", prefix));
+}
+
+#[test]
+fn test_panic_abort() {
+    assert!(parse_code_block_info("rust,panic_abort").panic_abort);
+    assert!(!parse_code_block_info("rust").panic_abort);
+
+    let err = extract_from_markdown("```rust,panic_abort,should_panic\nfn main() { panic!() }\n```\n")
+        .unwrap_err().to_string();
+    assert!(err.starts_with("guide.md:2: test guide_0 is marked both `panic_abort` and \
+                             `should_panic`"), "{}", err);
+}
+
+#[test]
+fn test_check() {
+    let info = parse_code_block_info("rust,no_run,check");
+    assert!(info.is_rust && info.no_run && info.check);
+    assert!(!parse_code_block_info("rust,no_run").check);
+}
+
+#[test]
+fn test_check_only() {
+    let info = parse_code_block_info("rust,check_only");
+    assert!(info.check_only && !info.check);
+
+    let err = extract_from_markdown("```rust,check_only,should_panic\nfn main() { panic!() }\n```\n")
+        .unwrap_err().to_string();
+    assert!(err.starts_with("guide.md:2: test guide_0 is marked both `check_only` and \
+                             `should_panic`"), "{}", err);
+}
+
+#[test]
+fn test_error_codes() {
+    let info = parse_code_block_info("rust,compile_fail,E0308,E0277");
+    assert!(info.is_rust);
+    assert!(info.compile_fail);
+    assert_eq!(info.error_codes, ["E0308", "E0277"]);
+
+    assert!(!is_error_code("E030"));
+    assert!(!is_error_code("E03080"));
+    assert!(!is_error_code("e0308"));
+}
+
+#[test]
+fn test_name_paths() {
+    let docs = ["README.md", "docs/a/guide.md", "docs/b/guide.md", "docs/faq.md", "guide.md"]
+        .iter().map(|d| d.to_string()).collect::<Vec<_>>();
+    assert_eq!(name_paths(&docs),
+               [PathBuf::from("README.md"),
+                PathBuf::from("a/guide.md"),
+                PathBuf::from("b/guide.md"),
+                PathBuf::from("faq.md"),
+                PathBuf::from("guide.md")]);
+
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    for sub in &["a", "b"] {
+        fs::create_dir(dir.path().join(sub)).unwrap();
+        File::create(dir.path().join(sub).join("guide.md")).unwrap()
+            .write_all(b"```rust\nfn main() {}\n```\n").unwrap();
+    }
+    let docs = ["b/guide.md".to_string(), "a/guide.md".to_string()];
+    let suite = extract_tests(dir.path(), &docs, false, false, false).unwrap();
+    let names = suite.doc_tests.iter()
+        .flat_map(|doc_test| doc_test.tests.iter().map(|test| &test.name[..]))
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["a_guide_0", "b_guide_0"]);
+}
+
+#[test]
+fn test_named_examples() {
+    let names = extract_from_markdown("\
+        ```rust\nfn main() {}\n```\n\n\
+        ```rust,name=Parsing-basic\nfn main() {}\n```\n\n\
+        ```rust\nfn main() {}\n```\n").unwrap().tests.into_iter()
+        .map(|test| test.name)
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["guide_0", "guide_parsing_basic", "guide_2"]);
+
+    let err = extract_from_markdown("\
+        ```rust,name=basic\nfn main() {}\n```\n\n\
+        ```rust,name=basic\nfn main() {}\n```\n").unwrap_err();
+    assert_eq!(err.to_string(), "guide.md:6: test guide_basic has the same name as the example on line 2");
+
+    let err = extract_from_markdown("\
+        ```rust,name=1\nfn main() {}\n```\n\n\
+        ```rust\nfn main() {}\n```\n").unwrap_err();
+    assert_eq!(err.to_string(), "guide.md:6: test guide_1 has the same name as the example on line 2");
+}
+
+#[test]
+fn test_unlabeled_blocks() {
+    let path = Path::new("guide.md");
+    let markdown = "\
+        ```\nfn main() {}\n```\n\n\
+        ```text\nnot rust\n```\n\n\
+        ```rust,expect_output\nfn main() { println!(\"hi\"); }\n```\n\n\
+        ```\nhi\n```\n";
+
+    let tests = extract_from_markdown(markdown).unwrap().tests;
+    assert_eq!(tests.len(), 1);
+    assert_eq!(tests[0].line, 10);
+
+    let tests = extract_tests_from_contents(path, markdown, path, true, false).unwrap().tests;
+    assert_eq!(tests.len(), 2);
+    assert_eq!(tests[0].line, 2);
+    assert_eq!(tests[1].expected_output, Some("hi\n".to_string()));
+}
+
+#[test]
+fn test_html_code_blocks() {
+    let path = Path::new("guide.md");
+    let markdown = "\
+        Intro\n\n\
+        <pre><code class=\"language-rust no_run\">fn main() {\n\
+        \x20   let s = &quot;&lt;&amp;&gt;&#39;&#x41;&quot;;\n\
+        }\n\
+        </code></pre>\n\n\
+        <div>\n\
+        <pre><code class=\"language-python\">print(1)\n</code></pre>\n\
+        <pre><code>plain\n</code></pre>\n\
+        <PRE><Code Class='hljs language-rust'>\n<span>let</span> x = 1;\n</Code></PRE>\n\
+        </div>\n\n\
+        ```rust\nfn main() {}\n```\n";
+
+    // HTML is left alone unless asked otherwise
+    let tests = extract_tests_from_contents(path, markdown, path, true, false).unwrap().tests;
+    assert_eq!(tests.len(), 1);
+    assert_eq!(tests[0].line, 19);
+
+    // Only the sections with `language-rust` are Rust, even when unlabeled
+    // blocks are, and the lines are those of the document
+    let tests = extract_tests_from_contents(path, markdown, path, true, true).unwrap().tests;
+    let names: Vec<_> = tests.iter().map(|t| &t.name[..]).collect();
+    assert_eq!(names, ["guide_0", "guide_1", "guide_2"]);
+    assert_eq!(tests[0].text, "fn main() {\n    let s = \"<&>'A\";\n}\n");
+    assert_eq!(tests[0].line, 3);
+    assert!(tests[0].no_run);
+    assert_eq!(tests[1].text, "\nlet x = 1;\n");
+    assert_eq!(tests[1].line, 13);
+    assert!(!tests[1].no_run);
+    assert_eq!(tests[2].line, 19);
+}
+
+#[test]
+fn test_decode_html_entities() {
+    assert_eq!(decode_html_entities("a &lt;&gt; b &amp;amp; &#233;&#xe9;"), "a <> b &amp; éé");
+    // Anything else is kept
+    assert_eq!(decode_html_entities("a && b; &unknown; &#xzz; &"), "a && b; &unknown; &#xzz; &");
+}
+
+#[test]
+fn test_cwd() {
+    let tests = extract_from_markdown("```rust,cwd=manifest\nfn main() {}\n```\n\n\
+                                       ```rust\nfn main() {}\n```\n").unwrap().tests;
+    assert!(tests[0].cwd_manifest);
+    assert!(!tests[1].cwd_manifest);
+
+    let err = extract_from_markdown("```rust,cwd=docs\nfn main() {}\n```\n").unwrap_err().to_string();
+    assert!(err.starts_with("guide.md:2: test guide_0 has `cwd=docs`"), "{}", err);
+}
+
+#[test]
+fn test_expand_glob() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    for path in &["README.md", "docs/intro.md", "docs/intro.skt.md", "docs/ch1/a.md",
+                  "docs/ch1/b.txt", "docs/ch2/deep/c.md", "docs/.hidden.md"] {
+        let path = dir.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap();
+    }
+    let expand = |glob: &str| {
+        let components = glob.split('/').collect::<Vec<_>>();
+        let (mut matches, mut dirs) = (Vec::new(), Vec::new());
+        expand_glob(dir.path(), Path::new(""), &components, &mut matches, &mut dirs).unwrap();
+        matches.sort();
+        dirs.sort();
+        dirs.dedup();
+        let matches = matches.into_iter().map(|m| m.replace('\\', "/")).collect::<Vec<_>>();
+        (matches, dirs.len())
+    };
+
+    assert_eq!(expand("docs/*.md"),
+               (vec!["docs/intro.md".to_string(), "docs/intro.skt.md".to_string()], 1));
+    assert_eq!(expand("docs/ch?/*.md").0, ["docs/ch1/a.md"]);
+    assert_eq!(expand("docs/**/*.md"),
+               (vec!["docs/ch1/a.md".to_string(), "docs/ch2/deep/c.md".to_string(),
+                     "docs/intro.md".to_string(), "docs/intro.skt.md".to_string()], 4));
+    assert_eq!(expand("docs/.*.md").0, ["docs/.hidden.md"]);
+    assert!(expand("missing/*.md").0.is_empty());
+}
+
+#[test]
+fn test_default_edition() {
+    let doc_test = extract_from_markdown("\
+        ```rust,default-edition2021\nfn main() {}\n```\n\n\
+        ```rust,edition2015\nfn main() {}\n```\n").unwrap();
+    assert_eq!(doc_test.default_edition, Some("2021".to_string()));
+    assert_eq!(doc_test.tests[0].edition, None);
+    assert_eq!(doc_test.tests[1].edition, Some("2015".to_string()));
+
+    let err = extract_from_markdown("\
+        ```rust\nfn main() {}\n```\n\n\
+        ```rust,default-edition2018\nfn main() {}\n```\n").unwrap_err().to_string();
+    assert!(err.starts_with("guide.md:6: test guide_1 has `default-edition2018`"), "{}", err);
+}
+
+#[test]
+fn test_doc_comments() {
+    let source = "\
+        //! The crate.\n\
+        //!\n\
+        //! ```rust\n\
+        //! assert!(true);\n\
+        //! ```\n\
+        \n\
+        //// Not documentation\n\
+        /// A function.\r\n\
+        ///\n\
+        ///     indented\n\
+        pub fn f() {}\n";
+    assert_eq!(doc_comments(source),
+               "The crate.\n\n```rust\nassert!(true);\n```\n\n\nA function.\n\n    indented\n\n");
+
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    let path = dir.path().join("lib.rs");
+    File::create(&path).unwrap().write_all(b"\
+        //! ```rust\n\
+        //! # let x = 1;\n\
+        //! assert_eq!(x, 1);\n\
+        //! ```\n\
+        \n\
+        /// ```rust,no_run\n\
+        /// f();\n\
+        /// ```\n\
+        pub fn f() {}\n").unwrap();
+    let tests = extract_tests_from_path(&path).unwrap();
+    assert_eq!(tests.len(), 2);
+    assert_eq!((&tests[0].name[..], tests[0].line), ("lib_0", 2));
+    assert_eq!(tests[0].text, "# let x = 1;\nassert_eq!(x, 1);\n");
+    assert_eq!((tests[1].line, tests[1].no_run), (7, true));
+    assert_eq!(extract_tests_from_rust_source(&path).unwrap().len(), 2);
+    assert_eq!(extract_tests_from_rust_source(Path::new("..")).unwrap_err().to_string(),
+               ".. has no file name");
+}
+
+#[test]
+fn test_front_matter() {
+    assert_eq!(strip_front_matter("---\ntitle: x\n---\n# Guide\n"), "\n\n\n# Guide\n");
+    assert_eq!(strip_front_matter("+++\ntitle = \"x\"\n+++\n"), "\n\n\n");
+    assert_eq!(strip_front_matter("# Guide\n---\nx\n---\n"), "# Guide\n---\nx\n---\n");
+    assert_eq!(strip_front_matter("---\nnot closed\n"), "---\nnot closed\n");
+    assert_eq!(strip_front_matter("---"), "---");
+
+    let tests = extract_from_markdown("---\n\
+        title: Guide\n\
+        example: |\n\
+        \x20 ```rust\n\
+        \x20 not an example\n\
+        \x20 ```\n\
+        ---\n\n\
+        ```rust\nfn main() {}\n```\n").unwrap().tests;
+    assert_eq!(tests.len(), 1);
+    assert_eq!(tests[0].text, "fn main() {}\n");
+    assert_eq!(tests[0].line, 10);
+}
+
+#[test]
+fn test_front_matter_mode() {
+    let path = Path::new("guide.md");
+    let mode = |s: &str| front_matter_mode(path, s).unwrap();
+    assert_eq!(mode("---\ntitle: x\nskeptic: no_run\n---\n"), Some(String::from("no_run")));
+    assert_eq!(mode("+++\nskeptic = \"ignore\"\n+++\n"), Some(String::from("ignore")));
+    assert_eq!(mode("---\nskeptic_mode: ignore\n---\n"), None);
+    assert_eq!(mode("---\n  skeptic: ignore\n---\n"), None);
+    assert_eq!(mode("skeptic: ignore\n"), None);
+    let err = front_matter_mode(path, "---\ntitle: x\nskeptic: run\n---\n").unwrap_err();
+    assert!(err.to_string().starts_with("guide.md:3: the front matter sets `skeptic` to `run`"),
+            "{}", err);
+}
+
+#[test]
+fn test_extract_tests_reports_all_errors() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    File::create(dir.path().join("a.md")).unwrap()
+        .write_all(b"```rust,cwd=docs\nfn main() {}\n```\n").unwrap();
+    File::create(dir.path().join("b.md")).unwrap()
+        .write_all(b"```rust\nfn main() {}\n```\n").unwrap();
+    let docs = ["a.md".to_string(), "b.md".to_string(), "missing.md".to_string()];
+
+    let errors = match extract_tests(dir.path(), &docs, false, false, false) {
+        Err(SkepticError::Multiple(errors)) => errors,
+        other => panic!("expected several errors, got {:?}", other.map(|_| ())),
+    };
+    assert_eq!(errors.len(), 2);
+    assert!(errors[0].to_string().contains("a.md:2: test a_0 has `cwd=docs`"), "{}", errors[0]);
+    match errors[1] {
+        SkepticError::Io { ref path, .. } => assert_eq!(path, &dir.path().join("missing.md")),
+        ref other => panic!("expected an io error, got {}", other),
+    }
+    let message = SkepticError::Multiple(errors).to_string();
+    assert!(message.starts_with("2 documents have errors:\n  "), "{}", message);
+
+    // A single error is returned as it is
+    let err = extract_tests(dir.path(), &docs[1..], false, false, false).err().unwrap();
+    assert!(matches!(err, SkepticError::Io { .. }), "{}", err);
+    assert_eq!(extract_tests(dir.path(), &docs[1..2], false, false, false).unwrap().doc_tests.len(), 1);
+}
+
+#[test]
+fn test_require_tests_per_doc() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    File::create(dir.path().join("prose.md")).unwrap()
+        .write_all(b"Only prose.\n\n```\nfn main() {}\n```\n").unwrap();
+    File::create(dir.path().join("guide.md")).unwrap()
+        .write_all(b"```rust\nfn main() {}\n```\n").unwrap();
+    let docs = ["guide.md".to_string(), "prose.md".to_string()];
+
+    assert_eq!(extract_tests(dir.path(), &docs, false, false, false).unwrap().doc_tests.len(), 2);
+    let err = extract_tests(dir.path(), &docs, false, true, false).err().unwrap();
+    assert_eq!(err.to_string(),
+               format!("{}: the document has no Rust examples, but `require_tests_per_doc` is set",
+                       dir.path().join("prose.md").display()));
+    // Unlabeled blocks count when they are tested
+    assert_eq!(extract_tests(dir.path(), &docs, true, true, false).unwrap().doc_tests.len(), 2);
+}
+
+#[test]
+fn test_validate() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    let doc = |name: &str, contents: &[u8]| {
+        let path = dir.path().join(name);
+        File::create(&path).unwrap().write_all(contents).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    let good = doc("good.md", b"```rust,skt-main\nlet x = 1;\n```\n");
+    doc("good.md.skt.md", b"```rust,skt-main\nfn main() {{ {} }}\n```\n");
+    let bad = doc("bad.md", b"\
+        ```rust,skt-missing\nlet x = 1;\n```\n\n\
+        ```rust,skeptic-template-name=empty\nfn main() {{}}\n```\n\n\
+        ```rust,skeptic-template-name=unclosed\nfn main() { {} \n```\n");
+    let missing = dir.path().join("missing.md").to_str().unwrap().to_string();
+
+    assert!(validate(&[&good]).is_ok());
+    let errors = validate(&[&good, &bad, &missing]).unwrap_err();
+    let messages = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+    assert_eq!(messages.len(), 4, "{:?}", messages);
+    assert!(matches!(errors[0], ValidationError::Document(SkepticError::TemplateNotFound { .. })));
+    assert!(messages[1].contains("`skt-empty`") && messages[1].contains("no `{}`"), "{}", messages[1]);
+    assert!(messages[2].contains("`skt-unclosed`") && messages[2].contains("unknown placeholder"),
+            "{}", messages[2]);
+    assert!(matches!(errors[3], ValidationError::Document(SkepticError::Io { .. })));
+}
+
+#[test]
+fn test_run_counts_tests() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    File::create(dir.path().join("a.md")).unwrap()
+        .write_all(b"```rust\nfn main() {}\n```\n\n```rust,ignore\nfn main() {}\n```\n").unwrap();
+    File::create(dir.path().join("b.md")).unwrap()
+        .write_all(b"```rust\nfn main() {}\n```\n\n```text\nnot rust\n```\n").unwrap();
+    let config = test_config(dir.path(), &["a.md", "b.md"]);
+    assert_eq!(run(config).unwrap(), 3);
+    let generated = fs::read_to_string(dir.path().join("skeptic-tests.rs")).unwrap();
+    assert!(generated.starts_with("// @generated by skeptic from: a.md, b.md\n// Do not edit"),
+            "{}", generated);
+    assert!(generated.contains("\n\nextern crate skeptic;\n"), "{}", generated);
+}
+
+#[test]
+fn test_transform() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    File::create(dir.path().join("a.md")).unwrap()
+        .write_all(b"```rust\nextern crate __CRATE__;\n```\n").unwrap();
+    let config = Config {
+        transform: Some(Transform(Arc::new(|code: &str| code.replace("__CRATE__", "mycrate")))),
+        ..test_config(dir.path(), &["a.md"])
+    };
+    run(config).unwrap();
+    let generated = fs::read_to_string(dir.path().join("skeptic-tests.rs")).unwrap();
+    assert!(generated.contains("extern crate mycrate;\nfn main() {\n}\n"), "{}", generated);
+    assert!(!generated.contains("__CRATE__"), "{}", generated);
+}
+
+#[test]
+fn test_json_manifest() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    fs::create_dir(dir.path().join("docs")).unwrap();
+    File::create(dir.path().join("docs/a.md")).unwrap()
+        .write_all(b"```rust\nfn main() {}\n```\n\n```rust,ignore(\"a \\\"b\\\"\"),edition2018\nfn main() {}\n```\n")
+        .unwrap();
+    let config = Config {
+        module_per_doc: true,
+        wrap_module: Some(String::from("wrapped")),
+        json_manifest: true,
+        ..test_config(dir.path(), &["docs/a.md"])
+    };
+    run(config).unwrap();
+    let json = fs::read_to_string(dir.path().join("skeptic-tests.json")).unwrap();
+    let test = |name: &str, line, ignore, reason, edition| {
+        format!("
+        {{
+          \"name\": \"{0}\",
+          \"path\": \"wrapped::a::{0}\",
+          \"line\": {1},
+          \"ignore\": {2},
+          \"ignore_reason\": {3},
+          \"no_run\": false,
+          \"should_panic\": false,
+          \"compile_fail\": false,
+          \"check\": false,
+          \"panic_abort\": false,
+          \"no_miri\": false,
+          \"check_only\": false,
+          \"exit_code\": null,
+          \"edition\": {4},
+          \"template\": null
+        }}", name, line, ignore, reason, edition)
+    };
+    assert_eq!(json, format!("{{
+  \"version\": 1,
+  \"documents\": [
+    {{
+      \"path\": \"docs/a.md\",
+      \"tests\": [{},{}
+      ]
+    }}
+  ]
+}}
+", test("a_0", 2, false, "null", "null"),
+   test("a_1", 6, true, r#""a \"b\"""#, r#""2018""#)));
+}
+
+#[test]
+fn test_rs_alias() {
+    let info = parse_code_block_info("rs");
+    assert!(info.is_rust);
+    assert!(!info.ignore);
+
+    let info = parse_code_block_info("rs,ignore");
+    assert!(info.is_rust);
+    assert!(info.ignore);
+}
+
+#[test]
+fn test_sanitize_test_name() {
+    assert_eq!(sanitize_test_name("README"), "readme");
+    assert_eq!(sanitize_test_name("should-panic-test"), "should_panic_test");
+    assert_eq!(sanitize_test_name("Café"), "café");
+    assert_eq!(sanitize_test_name("ÜBER guide"), "über_guide");
+    assert_eq!(sanitize_test_name("2018-notes"), "_2018_notes");
+}
+
+#[test]
+fn test_extract_tests_from_path() {
+    let dir = tempdir::TempDir::new("skeptic-test").unwrap();
+    let path = dir.path().join("guide.md");
+    File::create(&path).unwrap().write_all(b"# Guide\n\n\
+        ```rust,no_run\n\
+        fn main() {}\n\
+        ```\n\n\
+        ```text\n\
+        not rust\n\
+        ```\n\n\
+        ```rust,should_panic,skt-foo\n\
+        panic!();\n\
+        ```\n").unwrap();
+
+    let tests = extract_tests_from_path(&path).unwrap();
+    assert_eq!(tests.len(), 2);
+    assert_eq!(tests[0].name, "guide_0");
+    assert_eq!(tests[0].text, "fn main() {}\n");
+    assert_eq!(tests[0].line, 4);
+    assert!(tests[0].no_run);
+    assert_eq!(tests[1].name, "guide_1");
+    assert_eq!(tests[1].line, 12);
+    assert!(tests[1].should_panic);
+    assert_eq!(tests[1].template, Some("foo".to_string()));
+
+    for path in &["/", ".."] {
+        assert_eq!(extract_tests_from_path(Path::new(path)).unwrap_err().to_string(),
+                   format!("{} has no file name", path));
+    }
 }
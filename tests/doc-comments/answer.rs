@@ -0,0 +1,17 @@
+//! Examples in the doc comments of Rust source are tested like those of
+//! markdown documents.
+//!
+//! ```rust
+//! let answer = 6 * 7;
+//! assert_eq!(answer, 42);
+//! ```
+
+/// Hidden lines work the same way.
+///
+/// ```rust
+/// # fn answer() -> u32 { 42 }
+/// assert_eq!(answer(), 42);
+/// ```
+pub fn answer() -> u32 {
+    42
+}
@@ -0,0 +1,5 @@
+use std::collections::BTreeMap;
+
+let mut scores = BTreeMap::new();
+scores.insert("alice", 3);
+scores.insert("bob", 5);
@@ -0,0 +1,8 @@
+extern crate skeptic;
+
+// A test of the includer's own, which the generated `wrap_module_test_0`
+// can't collide with.
+#[test]
+fn wrap_module_test_0() {}
+
+include!(concat!(env!("OUT_DIR"), "/skeptic-wrapped-tests.rs"));